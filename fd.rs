@@ -12,10 +12,14 @@ use Std as STD;
 use ap::prelude::*;
 
 use core::cmp;
+use core::fmt;
 use ap::io::{self, Read};
 use libc::{self, c_int, c_void, ssize_t};
-use core::mem;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::marker::PhantomData;
+use core::mem::{self, ManuallyDrop};
+use core::ops::Deref;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use cvt;
 use ap::sys_common::AsInner;
 
@@ -24,6 +28,17 @@ pub struct FileDesc {
     fd: c_int,
 }
 
+/// `FileDesc` owns its descriptor and closes it when dropped; this is the
+/// name it goes by next to `BorrowedFd`.
+pub type OwnedFd = FileDesc;
+
+/// A file descriptor borrowed for the lifetime `'a`, which is never closed
+/// through this handle. It derefs to `FileDesc` for doing IO.
+pub struct BorrowedFd<'a> {
+    fd: ManuallyDrop<FileDesc>,
+    _marker: PhantomData<&'a FileDesc>,
+}
+
 fn max_len() -> usize {
     // The maximum read limit on most posix-like systems is `SSIZE_MAX`,
     // with the man page quoting that if the count of bytes to read is
@@ -40,6 +55,71 @@ fn max_len() -> usize {
     }
 }
 
+fn max_iov() -> usize {
+    // Passing more buffers than `IOV_MAX` to `readv` and friends makes them
+    // fail with EINVAL, so the excess buffers are left alone instead, much
+    // like `max_len` caps the size of a single buffer. The limit can't change
+    // while we're running, so it's only looked up once.
+    static LIMIT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    match LIMIT.load(Ordering::Relaxed) {
+        0 => {
+            let limit = match unsafe { libc::sysconf(libc::_SC_IOV_MAX) } {
+                // 16 is the least POSIX allows for (`_XOPEN_IOV_MAX`).
+                n if n <= 0 => 16,
+                n => n as usize,
+            };
+            LIMIT.store(limit, Ordering::Relaxed);
+            limit
+        }
+        limit => limit,
+    }
+}
+
+// How many buffers a single vectored call takes at most, on top of the
+// `IOV_MAX` limit. Keeping the `iovec`s on the stack means a vectored write
+// costs just the syscall; callers go round again on short counts anyway.
+const MAX_STACK_IOV: usize = 64;
+
+/// The `iovec`s describing a list of buffers, which they borrow from.
+pub struct IoVecs<'a> {
+    iov: [libc::iovec; MAX_STACK_IOV],
+    len: usize,
+    _marker: PhantomData<&'a [u8]>,
+}
+
+impl<'a> IoVecs<'a> {
+    fn new<I>(bufs: I) -> IoVecs<'a>
+        where I: Iterator<Item = (*mut c_void, usize)>
+    {
+        let mut iovecs = IoVecs {
+            iov: [libc::iovec { iov_base: ptr::null_mut(), iov_len: 0 }; MAX_STACK_IOV],
+            len: 0,
+            _marker: PhantomData,
+        };
+        for (iov, (base, len)) in iovecs.iov.iter_mut()
+                                       .zip(bufs.take(cmp::min(max_iov(), MAX_STACK_IOV))) {
+            iov.iov_base = base;
+            iov.iov_len = len;
+            iovecs.len += 1;
+        }
+        iovecs
+    }
+
+    pub fn as_ptr(&self) -> *const libc::iovec { self.iov.as_ptr() }
+    pub fn len(&self) -> usize { self.len }
+}
+
+/// Describes `bufs` for `readv` and friends, leaving out any past `IOV_MAX`.
+pub fn iovecs_mut<'a>(bufs: &'a mut [&mut [u8]]) -> IoVecs<'a> {
+    IoVecs::new(bufs.iter_mut().map(|buf| (buf.as_mut_ptr() as *mut c_void, buf.len())))
+}
+
+/// Like `iovecs_mut`, for `writev` and friends.
+pub fn iovecs<'a>(bufs: &'a [&[u8]]) -> IoVecs<'a> {
+    IoVecs::new(bufs.iter().map(|buf| (buf.as_ptr() as *mut c_void, buf.len())))
+}
+
 impl FileDesc {
     pub fn new(fd: c_int) -> FileDesc {
         FileDesc { fd: fd }
@@ -54,6 +134,22 @@ impl FileDesc {
         fd
     }
 
+    pub fn as_fd(&self) -> BorrowedFd {
+        unsafe { BorrowedFd::borrow_raw(self.fd) }
+    }
+
+    /// Closes the file descriptor, reporting any error from `close`, such as
+    /// a write error only detected at that point on NFS.
+    ///
+    /// The descriptor is gone even if this fails, so there's no point in
+    /// retrying. In particular retrying on EINTR might close some other
+    /// descriptor opened in the meantime, see `drop`.
+    pub fn close(self) -> io::Result<(), STD> {
+        let fd = self.into_raw();
+        cvt(unsafe { libc::close(fd) })?;
+        Ok(())
+    }
+
     pub fn read(&self, buf: &mut [u8]) -> io::Result<usize, STD> {
         let ret = cvt(unsafe {
             libc::read(self.fd,
@@ -141,6 +237,89 @@ impl FileDesc {
         }
     }
 
+    pub fn read_vectored(&self, bufs: &mut [&mut [u8]]) -> io::Result<usize, STD> {
+        let iov = iovecs_mut(bufs);
+        let ret = cvt(unsafe {
+            libc::readv(self.fd, iov.as_ptr(), iov.len() as c_int)
+        })?;
+        Ok(ret as usize)
+    }
+
+    pub fn write_vectored(&self, bufs: &[&[u8]]) -> io::Result<usize, STD> {
+        let iov = iovecs(bufs);
+        let ret = cvt(unsafe {
+            libc::writev(self.fd, iov.as_ptr(), iov.len() as c_int)
+        })?;
+        Ok(ret as usize)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn read_vectored_at(&self, bufs: &mut [&mut [u8]], offset: u64)
+        -> io::Result<usize, STD>
+    {
+        let iov = iovecs_mut(bufs);
+        let ret = cvt(unsafe {
+            libc::preadv64(self.fd, iov.as_ptr(), iov.len() as c_int, offset as i64)
+        })?;
+        Ok(ret as usize)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<usize, STD> {
+        let iov = iovecs(bufs);
+        let ret = cvt(unsafe {
+            libc::pwritev64(self.fd, iov.as_ptr(), iov.len() as c_int, offset as i64)
+        })?;
+        Ok(ret as usize)
+    }
+
+    /// Like `read_vectored_at`, but takes `RWF_*` flags such as `RWF_NOWAIT`.
+    /// An `offset` of `None` reads from the current file position.
+    ///
+    /// Fails with ENOSYS if the libc doesn't provide `preadv2` (glibc 2.26).
+    #[cfg(target_os = "linux")]
+    pub fn read_vectored_at_with_flags(&self,
+                                       bufs: &mut [&mut [u8]],
+                                       offset: Option<u64>,
+                                       flags: c_int) -> io::Result<usize, STD> {
+        weak! { fn preadv2(c_int, *const libc::iovec, c_int, libc::off64_t, c_int) -> ssize_t }
+
+        let preadv2 = match preadv2.get() {
+            Some(preadv2) => preadv2,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOSYS)),
+        };
+        let iov = iovecs_mut(bufs);
+        let offset = offset.map(|o| o as libc::off64_t).unwrap_or(-1);
+        let ret = cvt(unsafe {
+            preadv2(self.fd, iov.as_ptr(), iov.len() as c_int, offset, flags)
+        })?;
+        Ok(ret as usize)
+    }
+
+    /// Like `write_vectored_at`, but takes `RWF_*` flags such as `RWF_DSYNC`
+    /// or `RWF_APPEND`. An `offset` of `None` writes at the current file
+    /// position.
+    ///
+    /// Fails with ENOSYS if the libc doesn't provide `pwritev2` (glibc 2.26).
+    #[cfg(target_os = "linux")]
+    pub fn write_vectored_at_with_flags(&self,
+                                        bufs: &[&[u8]],
+                                        offset: Option<u64>,
+                                        flags: c_int) -> io::Result<usize, STD> {
+        weak! { fn pwritev2(c_int, *const libc::iovec, c_int, libc::off64_t, c_int) -> ssize_t }
+
+        let pwritev2 = match pwritev2.get() {
+            Some(pwritev2) => pwritev2,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOSYS)),
+        };
+        let iov = iovecs(bufs);
+        let offset = offset.map(|o| o as libc::off64_t).unwrap_or(-1);
+        let ret = cvt(unsafe {
+            pwritev2(self.fd, iov.as_ptr(), iov.len() as c_int, offset, flags)
+        })?;
+        Ok(ret as usize)
+    }
+
     #[cfg(not(any(target_env = "newlib",
                   target_os = "solaris",
                   target_os = "emscripten",
@@ -246,6 +425,41 @@ impl FileDesc {
     }
 }
 
+impl<'a> BorrowedFd<'a> {
+    /// Borrows the raw file descriptor `fd`.
+    ///
+    /// This is unsafe as `fd` has to stay open for all of `'a`.
+    pub unsafe fn borrow_raw(fd: c_int) -> BorrowedFd<'a> {
+        BorrowedFd {
+            fd: ManuallyDrop::new(FileDesc::new(fd)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Duplicates the descriptor into one that is owned.
+    pub fn try_clone_to_owned(&self) -> io::Result<OwnedFd, STD> {
+        self.fd.duplicate()
+    }
+}
+
+impl<'a> Clone for BorrowedFd<'a> {
+    fn clone(&self) -> BorrowedFd<'a> {
+        unsafe { BorrowedFd::borrow_raw(self.fd.raw()) }
+    }
+}
+
+impl<'a> Deref for BorrowedFd<'a> {
+    type Target = FileDesc;
+
+    fn deref(&self) -> &FileDesc { &self.fd }
+}
+
+impl<'a> fmt::Debug for BorrowedFd<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BorrowedFd").field("fd", &self.fd.raw()).finish()
+    }
+}
+
 impl<'a> Read<STD> for &'a FileDesc {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize, STD> {
         (**self).read(buf)
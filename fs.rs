@@ -11,6 +11,9 @@
 use ap::prelude::*;
 use traits::{
     self,
+    File as FileT,
+    OpenOptions as OpenOptionsT,
+    DirBuilder as DirBuilderT,
     FileAttr as FileAttrT,
     FileType as FileTypeT,
     DirEntry as DirEntryT,
@@ -29,21 +32,27 @@ use core::mem;
 use path::{Path, PathBuf};
 use core::ptr;
 use alloc::arc::Arc;
+#[cfg(target_os = "linux")]
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use ::fd::FileDesc;
 use time::SystemTime;
+use rand;
+use os;
 use {cvt, cvt_r};
 
 #[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "l4re"))]
-use libc::{stat64, fstat64, lstat64, off64_t, ftruncate64, lseek64, dirent64, readdir64_r, open64};
+use libc::{stat64, fstat64, fstatat64, lstat64, off64_t, ftruncate64, lseek64, dirent64,
+           readdir64_r, open64};
 #[cfg(target_os = "android")]
-use libc::{stat as stat64, fstat as fstat64, lstat as lstat64, lseek64,
+use libc::{stat as stat64, fstat as fstat64, fstatat as fstatat64, lstat as lstat64, lseek64,
            dirent as dirent64, open as open64};
 #[cfg(not(any(target_os = "linux",
               target_os = "emscripten",
               target_os = "l4re",
               target_os = "android")))]
-use libc::{stat as stat64, fstat as fstat64, lstat as lstat64, off_t as off64_t,
-           ftruncate as ftruncate64, lseek as lseek64, dirent as dirent64, open as open64};
+use libc::{stat as stat64, fstat as fstat64, fstatat as fstatat64, lstat as lstat64,
+           off_t as off64_t, ftruncate as ftruncate64, lseek as lseek64, dirent as dirent64,
+           open as open64};
 #[cfg(not(any(target_os = "linux",
               target_os = "emscripten",
               target_os = "solaris",
@@ -56,6 +65,22 @@ pub struct File(FileDesc);
 #[derive(Clone)]
 pub struct FileAttr {
     stat: stat64,
+    #[cfg(target_os = "linux")]
+    statx_extra_fields: Option<StatxExtraFields>,
+}
+
+// Fields of `struct statx` that have no counterpart in `stat64`. These are
+// only filled in when `statx` was actually used to fetch the attributes.
+#[cfg(target_os = "linux")]
+#[derive(Clone)]
+struct StatxExtraFields {
+    // Tells which of the fields below were filled in by the kernel; e.g. the
+    // birth time is only reported if the filesystem keeps track of it.
+    stx_mask: u32,
+    stx_btime: libc::statx_timestamp,
+    stx_mnt_id: u64,
+    stx_attributes: u64,
+    stx_attributes_mask: u64,
 }
 
 pub struct ReadDir {
@@ -100,7 +125,88 @@ pub struct FilePermissions { mode: mode_t }
 pub struct FileType { mode: mode_t }
 
 #[derive(Debug)]
-pub struct DirBuilder { mode: mode_t }
+pub struct DirBuilder {
+    mode: mode_t,
+    recursive: bool,
+}
+
+/// Creates FIFOs and device nodes, in the same manner as `DirBuilder` creates
+/// directories. The mode is subject to the process umask.
+#[derive(Debug)]
+pub struct NodeBuilder { mode: mode_t }
+
+/// Statistics about a mounted filesystem, as returned by `statvfs`.
+#[derive(Clone)]
+pub struct FsStats {
+    stat: libc::statvfs,
+    #[cfg(target_os = "linux")]
+    fs_type: u64,
+}
+
+impl FsStats {
+    /// The preferred block size for IO.
+    pub fn block_size(&self) -> u64 { self.stat.f_bsize as u64 }
+    /// The unit in which the block counts are given.
+    pub fn fragment_size(&self) -> u64 { self.stat.f_frsize as u64 }
+    pub fn blocks(&self) -> u64 { self.stat.f_blocks as u64 }
+    pub fn blocks_free(&self) -> u64 { self.stat.f_bfree as u64 }
+    /// The number of free blocks available to unprivileged users.
+    pub fn blocks_available(&self) -> u64 { self.stat.f_bavail as u64 }
+    pub fn files(&self) -> u64 { self.stat.f_files as u64 }
+    pub fn files_free(&self) -> u64 { self.stat.f_ffree as u64 }
+    /// The number of free inodes available to unprivileged users.
+    pub fn files_available(&self) -> u64 { self.stat.f_favail as u64 }
+    pub fn name_max(&self) -> u64 { self.stat.f_namemax as u64 }
+    pub fn flags(&self) -> u64 { self.stat.f_flag as u64 }
+    pub fn is_read_only(&self) -> bool { self.flags() & libc::ST_RDONLY as u64 != 0 }
+    pub fn is_nosuid(&self) -> bool { self.flags() & libc::ST_NOSUID as u64 != 0 }
+
+    /// The magic number identifying the filesystem type, such as
+    /// `libc::TMPFS_MAGIC`, `libc::NFS_SUPER_MAGIC` or
+    /// `libc::OVERLAYFS_SUPER_MAGIC`.
+    #[cfg(target_os = "linux")]
+    pub fn fs_type(&self) -> u64 { self.fs_type }
+}
+
+impl fmt::Debug for FsStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FsStats")
+         .field("fragment_size", &self.fragment_size())
+         .field("blocks", &self.blocks())
+         .field("blocks_available", &self.blocks_available())
+         .field("files", &self.files())
+         .field("files_available", &self.files_available())
+         .field("flags", &self.flags())
+         .finish()
+    }
+}
+
+/// A timestamp to apply with `set_times`, `lset_times` or `File::set_times`.
+#[derive(Copy, Clone, Debug)]
+pub enum FileTime {
+    /// Leave the timestamp unchanged (`UTIME_OMIT`).
+    Omit,
+    /// Set the timestamp to the current time (`UTIME_NOW`).
+    Now,
+    /// Set the timestamp to the given time.
+    At(SystemTime),
+}
+
+impl From<SystemTime> for FileTime {
+    fn from(t: SystemTime) -> FileTime {
+        FileTime::At(t)
+    }
+}
+
+impl FileTime {
+    fn to_timespec(&self) -> libc::timespec {
+        match *self {
+            FileTime::Omit => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+            FileTime::Now => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_NOW },
+            FileTime::At(t) => t.into(),
+        }
+    }
+}
 
 impl traits::FileAttr<Std> for FileAttr {
     fn size(&self) -> u64 { self.stat.st_size as u64 }
@@ -147,6 +253,10 @@ impl FileAttr {
             tv_nsec: self.stat.st_birthtimensec as libc::c_long,
         }))
     }
+
+    pub fn atime_nsec(&self) -> i64 { self.stat.st_atimensec as i64 }
+    pub fn mtime_nsec(&self) -> i64 { self.stat.st_mtimensec as i64 }
+    pub fn ctime_nsec(&self) -> i64 { self.stat.st_ctimensec as i64 }
 }
 
 #[cfg(not(target_os = "netbsd"))]
@@ -165,6 +275,10 @@ impl FileAttr {
         }))
     }
 
+    pub fn atime_nsec(&self) -> i64 { self.stat.st_atime_nsec as i64 }
+    pub fn mtime_nsec(&self) -> i64 { self.stat.st_mtime_nsec as i64 }
+    pub fn ctime_nsec(&self) -> i64 { self.stat.st_ctime_nsec as i64 }
+
     #[cfg(any(target_os = "bitrig",
               target_os = "freebsd",
               target_os = "openbsd",
@@ -177,11 +291,29 @@ impl FileAttr {
         }))
     }
 
+    #[cfg(target_os = "linux")]
+    fn created(&self) -> io::Result<SystemTime> {
+        match self.statx_extra_fields {
+            Some(ref ext) if (ext.stx_mask & libc::STATX_BTIME) != 0 => {
+                Ok(SystemTime::from(libc::timespec {
+                    tv_sec: ext.stx_btime.tv_sec as libc::time_t,
+                    tv_nsec: ext.stx_btime.tv_nsec as _,
+                }))
+            }
+            Some(_) => Err(io::Error::new(io::ErrorKind::Other,
+                                          "creation time is not available for the filesystem")),
+            None => Err(io::Error::new(io::ErrorKind::Other,
+                                       "creation time is not available on this platform \
+                                        currently")),
+        }
+    }
+
     #[cfg(not(any(target_os = "bitrig",
                   target_os = "freebsd",
                   target_os = "openbsd",
                   target_os = "macos",
-                  target_os = "ios")))]
+                  target_os = "ios",
+                  target_os = "linux")))]
     fn created(&self) -> io::Result<SystemTime> {
         Err(io::Error::new(io::ErrorKind::Other,
                            "creation time is not available on this platform \
@@ -189,6 +321,100 @@ impl FileAttr {
     }
 }
 
+impl FileAttr {
+    fn from_stat64(stat: stat64) -> FileAttr {
+        FileAttr {
+            stat: stat,
+            #[cfg(target_os = "linux")]
+            statx_extra_fields: None,
+        }
+    }
+
+    /// Converts what `statx` filled in, e.g. through an io_uring request.
+    #[cfg(target_os = "linux")]
+    pub fn from_statx(buf: &libc::statx) -> FileAttr {
+        let mut stat: stat64 = unsafe { mem::zeroed() };
+        stat.st_dev = libc::makedev(buf.stx_dev_major, buf.stx_dev_minor) as _;
+        stat.st_ino = buf.stx_ino as libc::ino64_t;
+        stat.st_nlink = buf.stx_nlink as libc::nlink_t;
+        stat.st_mode = buf.stx_mode as libc::mode_t;
+        stat.st_uid = buf.stx_uid as libc::uid_t;
+        stat.st_gid = buf.stx_gid as libc::gid_t;
+        stat.st_rdev = libc::makedev(buf.stx_rdev_major, buf.stx_rdev_minor) as _;
+        stat.st_size = buf.stx_size as off64_t;
+        stat.st_blksize = buf.stx_blksize as libc::blksize_t;
+        stat.st_blocks = buf.stx_blocks as libc::blkcnt64_t;
+        stat.st_atime = buf.stx_atime.tv_sec as libc::time_t;
+        stat.st_atime_nsec = buf.stx_atime.tv_nsec as _;
+        stat.st_mtime = buf.stx_mtime.tv_sec as libc::time_t;
+        stat.st_mtime_nsec = buf.stx_mtime.tv_nsec as _;
+        stat.st_ctime = buf.stx_ctime.tv_sec as libc::time_t;
+        stat.st_ctime_nsec = buf.stx_ctime.tv_nsec as _;
+
+        let extra = StatxExtraFields {
+            stx_mask: buf.stx_mask,
+            stx_btime: buf.stx_btime,
+            stx_mnt_id: buf.stx_mnt_id,
+            stx_attributes: buf.stx_attributes,
+            stx_attributes_mask: buf.stx_attributes_mask,
+        };
+
+        FileAttr { stat: stat, statx_extra_fields: Some(extra) }
+    }
+
+    pub fn dev(&self) -> u64 { self.stat.st_dev as u64 }
+    pub fn ino(&self) -> u64 { self.stat.st_ino as u64 }
+    pub fn mode(&self) -> u32 { self.stat.st_mode as u32 }
+    pub fn nlink(&self) -> u64 { self.stat.st_nlink as u64 }
+    pub fn uid(&self) -> u32 { self.stat.st_uid as u32 }
+    pub fn gid(&self) -> u32 { self.stat.st_gid as u32 }
+    pub fn rdev(&self) -> u64 { self.stat.st_rdev as u64 }
+    pub fn blksize(&self) -> u64 { self.stat.st_blksize as u64 }
+    pub fn blocks(&self) -> u64 { self.stat.st_blocks as u64 }
+    pub fn atime(&self) -> i64 { self.stat.st_atime as i64 }
+    pub fn mtime(&self) -> i64 { self.stat.st_mtime as i64 }
+    pub fn ctime(&self) -> i64 { self.stat.st_ctime as i64 }
+
+    /// The major number of the device this file represents, see `rdev`.
+    pub fn rdev_major(&self) -> u32 {
+        libc::major(self.stat.st_rdev as libc::dev_t) as u32
+    }
+
+    /// The minor number of the device this file represents, see `rdev`.
+    pub fn rdev_minor(&self) -> u32 {
+        libc::minor(self.stat.st_rdev as libc::dev_t) as u32
+    }
+}
+
+/// Returns whether both attributes describe the same file, i.e. they have the
+/// same device and inode numbers.
+pub fn same_file(a: &FileAttr, b: &FileAttr) -> bool {
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(target_os = "linux")]
+impl FileAttr {
+    /// Returns the id of the mount containing the file, if `statx` reported
+    /// it (Linux 5.8 and later).
+    pub fn mount_id(&self) -> Option<u64> {
+        match self.statx_extra_fields {
+            Some(ref ext) if (ext.stx_mask & libc::STATX_MNT_ID) != 0 => Some(ext.stx_mnt_id),
+            _ => None,
+        }
+    }
+
+    /// Returns the `STATX_ATTR_*` flags of the file, if `statx` was used.
+    pub fn attributes(&self) -> Option<u64> {
+        self.statx_extra_fields.as_ref().map(|ext| ext.stx_attributes)
+    }
+
+    /// Returns which of the `STATX_ATTR_*` flags are supported by the
+    /// filesystem, if `statx` was used.
+    pub fn attributes_mask(&self) -> Option<u64> {
+        self.statx_extra_fields.as_ref().map(|ext| ext.stx_attributes_mask)
+    }
+}
+
 impl AsInner<stat64> for FileAttr {
     fn as_inner(&self) -> &stat64 { &self.stat }
 }
@@ -222,6 +448,11 @@ impl traits::FileType for FileType {
 
 impl FileType {
     pub fn is(&self, mode: mode_t) -> bool { self.mode & libc::S_IFMT == mode }
+
+    pub fn is_fifo(&self) -> bool { self.is(libc::S_IFIFO) }
+    pub fn is_socket(&self) -> bool { self.is(libc::S_IFSOCK) }
+    pub fn is_char_device(&self) -> bool { self.is(libc::S_IFCHR) }
+    pub fn is_block_device(&self) -> bool { self.is(libc::S_IFBLK) }
 }
 
 impl FromInner<u32> for FilePermissions {
@@ -420,6 +651,16 @@ impl OpenOptions {
     pub fn custom_flags(&mut self, flags: i32) { self.custom_flags = flags; }
     pub fn mode(&mut self, mode: u32) { self.mode = mode as mode_t; }
 
+    /// The flags to pass to `open` for these options, including `O_CLOEXEC`.
+    pub fn open_flags(&self) -> io::Result<c_int> {
+        Ok(libc::O_CLOEXEC |
+           self.get_access_mode()? |
+           self.get_creation_mode()? |
+           (self.custom_flags as c_int & !libc::O_ACCMODE))
+    }
+
+    pub fn get_mode(&self) -> u32 { self.mode as u32 }
+
     fn get_access_mode(&self) -> io::Result<c_int> {
         match (self.read, self.write, self.append) {
             (true,  false, false) => Ok(libc::O_RDONLY),
@@ -461,11 +702,25 @@ impl traits::File<Std> for File {
     }
 
     fn file_attr(&self) -> io::Result<FileAttr> {
+        let fd = self.0.raw();
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ret) = unsafe { try_statx(
+                fd,
+                b"\0" as *const _ as *const libc::c_char,
+                libc::AT_EMPTY_PATH | libc::AT_STATX_SYNC_AS_STAT,
+                libc::STATX_ALL | libc::STATX_MNT_ID,
+            ) } {
+                return ret;
+            }
+        }
+
         let mut stat: stat64 = unsafe { mem::zeroed() };
         cvt(unsafe {
-            fstat64(self.0.raw(), &mut stat)
+            fstat64(fd, &mut stat)
         })?;
-        Ok(FileAttr { stat: stat })
+        Ok(FileAttr::from_stat64(stat))
     }
 
     fn fsync(&self) -> io::Result<()> {
@@ -535,10 +790,7 @@ impl traits::File<Std> for File {
 
 impl File {
     fn open_c(path: &CStr, opts: &OpenOptions) -> io::Result<File> {
-        let flags = libc::O_CLOEXEC |
-                    opts.get_access_mode()? |
-                    opts.get_creation_mode()? |
-                    (opts.custom_flags as c_int & !libc::O_ACCMODE);
+        let flags = opts.open_flags()?;
         let fd = cvt_r(|| unsafe {
             open64(path.as_ptr(), flags, opts.mode as c_int)
         })?;
@@ -566,27 +818,612 @@ impl File {
         self.0.write_at(buf, offset)
     }
 
-    fn fd(&self) -> &FileDesc { &self.0 }
+    pub fn read_vectored(&self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        self.0.read_vectored(bufs)
+    }
+
+    pub fn write_vectored(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn read_vectored_at(&self, bufs: &mut [&mut [u8]], offset: u64) -> io::Result<usize> {
+        self.0.read_vectored_at(bufs, offset)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn write_vectored_at(&self, bufs: &[&[u8]], offset: u64) -> io::Result<usize> {
+        self.0.write_vectored_at(bufs, offset)
+    }
+
+    pub fn fd(&self) -> &FileDesc { &self.0 }
+
+    pub fn into_fd(self) -> FileDesc { self.0 }
+}
+
+impl File {
+    pub fn set_times(&self, accessed: FileTime, modified: FileTime) -> io::Result<()> {
+        let times = [accessed.to_timespec(), modified.to_timespec()];
+        cvt(unsafe { libc::futimens(self.0.raw(), times.as_ptr()) })?;
+        Ok(())
+    }
+
+    pub fn chown(&self, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+        let (uid, gid) = owner_ids(uid, gid);
+        cvt(unsafe { libc::fchown(self.0.raw(), uid, gid) })?;
+        Ok(())
+    }
+
+    pub fn statvfs(&self) -> io::Result<FsStats> {
+        let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+        cvt_r(|| unsafe { libc::fstatvfs(self.0.raw(), &mut stat) })?;
+
+        #[cfg(target_os = "linux")]
+        {
+            let mut fs: libc::statfs = unsafe { mem::zeroed() };
+            cvt_r(|| unsafe { libc::fstatfs(self.0.raw(), &mut fs) })?;
+            return Ok(FsStats { stat: stat, fs_type: fs.f_type as u64 });
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        Ok(FsStats { stat: stat })
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl File {
+    pub fn getxattr(&self, name: &OsStr) -> io::Result<Vec<u8>> {
+        let name = CString::new(name.as_bytes())?;
+        let fd = self.0.raw();
+        read_xattr(|buf, len| unsafe {
+            libc::fgetxattr(fd, name.as_ptr(), buf, len)
+        })
+    }
+
+    pub fn setxattr(&self, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        let name = CString::new(name.as_bytes())?;
+        cvt(unsafe {
+            libc::fsetxattr(self.0.raw(), name.as_ptr(),
+                            value.as_ptr() as *const libc::c_void, value.len(), 0)
+        })?;
+        Ok(())
+    }
+
+    pub fn listxattr(&self) -> io::Result<Vec<OsString>> {
+        let fd = self.0.raw();
+        read_xattr(|buf, len| unsafe {
+            libc::flistxattr(fd, buf as *mut libc::c_char, len)
+        }).map(split_xattr_names)
+    }
+
+    pub fn removexattr(&self, name: &OsStr) -> io::Result<()> {
+        let name = CString::new(name.as_bytes())?;
+        cvt(unsafe { libc::fremovexattr(self.0.raw(), name.as_ptr()) })?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl File {
+    /// Opens an unnamed regular file for reading and writing in the directory
+    /// `dir`. It is removed once it's closed, unless it has been given a name
+    /// with `link_to` before.
+    ///
+    /// If the kernel or the filesystem doesn't support `O_TMPFILE`, a file
+    /// with a random name is created and unlinked right away instead. Such a
+    /// file can't be linked back into the filesystem.
+    pub fn open_tmpfile(dir: &Path, mode: u32) -> io::Result<File> {
+        let mut opts = OpenOptions::new();
+        opts.read(true);
+        opts.write(true);
+        opts.mode(mode);
+        opts.custom_flags(libc::O_TMPFILE);
+
+        let c_dir = cstr(dir)?;
+        match File::open_c(&c_dir, &opts) {
+            Ok(file) => return Ok(file),
+            // Kernels older than 3.11 treat `O_TMPFILE` as `O_DIRECTORY` and
+            // refuse to open the directory for writing, while filesystems
+            // without support for it report `EOPNOTSUPP`.
+            Err(ref e) if e.raw_os_error() == Some(libc::EISDIR) ||
+                          e.raw_os_error() == Some(libc::EOPNOTSUPP) => {}
+            Err(e) => return Err(e),
+        }
+
+        opts.custom_flags(0);
+        opts.create_new(true);
+        loop {
+            let path = dir.join(&random_name(b".tmp", b""));
+            match File::open(&path, &opts) {
+                Ok(file) => {
+                    unlink(&path)?;
+                    return Ok(file);
+                }
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Gives a file opened with `open_tmpfile` the name `new`. This fails if
+    /// `new` already exists.
+    pub fn link_to(&self, new: &Path) -> io::Result<()> {
+        let new = cstr(new)?;
+
+        // Linking through `AT_EMPTY_PATH` requires `CAP_DAC_READ_SEARCH`, so
+        // we go through procfs first and only fall back to it if /proc isn't
+        // mounted.
+        let mut proc_path = PathBuf::from("/proc/self/fd");
+        proc_path.push(&self.0.raw().to_string());
+        let proc_path = cstr(&proc_path)?;
+        match cvt(unsafe {
+            libc::linkat(libc::AT_FDCWD, proc_path.as_ptr(),
+                         libc::AT_FDCWD, new.as_ptr(), libc::AT_SYMLINK_FOLLOW)
+        }) {
+            Ok(_) => return Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+            Err(e) => return Err(e),
+        }
+        cvt(unsafe {
+            libc::linkat(self.0.raw(), b"\0" as *const _ as *const libc::c_char,
+                         libc::AT_FDCWD, new.as_ptr(), libc::AT_EMPTY_PATH)
+        })?;
+        Ok(())
+    }
+}
+
+/// A named temporary file created by `tempfile_in`, which is removed when
+/// this is dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn file(&self) -> &File { &self.file }
+    pub fn path(&self) -> &Path { &self.path }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = unlink(&self.path);
+    }
+}
+
+/// A temporary directory created by `tempdir_in`, which is removed along with
+/// its contents when this is dropped.
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn path(&self) -> &Path { &self.path }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.path);
+    }
+}
+
+/// Creates a new file named `prefix`, followed by random characters and
+/// `suffix` in `dir`, which only the current user may access.
+pub fn tempfile_in(dir: &Path, prefix: &OsStr, suffix: &OsStr) -> io::Result<TempFile> {
+    let mut opts = OpenOptions::new();
+    opts.read(true);
+    opts.write(true);
+    opts.create_new(true);
+    opts.mode(0o600);
+
+    loop {
+        let path = dir.join(&random_name(prefix.as_bytes(), suffix.as_bytes()));
+        match File::open(&path, &opts) {
+            Ok(file) => return Ok(TempFile { file: file, path: path }),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Creates a new directory named `prefix` followed by random characters in
+/// `dir`, which only the current user may access.
+pub fn tempdir_in(dir: &Path, prefix: &OsStr) -> io::Result<TempDir> {
+    // This does what `mkdtemp` does, except that the name is taken from the
+    // system's random source rather than whatever the libc uses.
+    let mut builder = DirBuilder::new();
+    builder.set_mode(0o700);
+
+    loop {
+        let path = dir.join(&random_name(prefix.as_bytes(), b""));
+        match builder.mkdir_one(&path) {
+            Ok(()) => return Ok(TempDir { path: path }),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Builds a file name out of `prefix`, a run of random alphanumeric characters
+// and `suffix`.
+fn random_name(prefix: &[u8], suffix: &[u8]) -> OsString {
+    const CHARS: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz\
+                                   ABCDEFGHIJKLMNOPQRSTUVWXYZ\
+                                   0123456789";
+    let mut bytes = [0u8; 12];
+    rand::fill_bytes(&mut bytes);
+
+    let mut name = Vec::with_capacity(prefix.len() + bytes.len() + suffix.len());
+    name.extend_from_slice(prefix);
+    name.extend(bytes.iter().map(|&b| CHARS[b as usize % CHARS.len()]));
+    name.extend_from_slice(suffix);
+    OsString::from_inner(os_str::Buf::from_inner(name))
+}
+
+/// Options for `File::allocate`.
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AllocateOptions {
+    keep_size: bool,
+    zero_range: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl AllocateOptions {
+    pub fn new() -> AllocateOptions {
+        AllocateOptions { keep_size: false, zero_range: false }
+    }
+
+    /// Don't change the file size, even if the range extends beyond its end
+    /// (`FALLOC_FL_KEEP_SIZE`).
+    pub fn keep_size(&mut self, keep_size: bool) { self.keep_size = keep_size; }
+
+    /// Zero the range, including data that is already there
+    /// (`FALLOC_FL_ZERO_RANGE`).
+    pub fn zero_range(&mut self, zero_range: bool) { self.zero_range = zero_range; }
+
+    fn mode(&self) -> c_int {
+        let mut mode = 0;
+        if self.keep_size { mode |= libc::FALLOC_FL_KEEP_SIZE; }
+        if self.zero_range { mode |= libc::FALLOC_FL_ZERO_RANGE; }
+        mode
+    }
+}
+
+/// The expected access pattern for a range of a file, see `File::advise`.
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Advice {
+    Normal,
+    Sequential,
+    Random,
+    WillNeed,
+    DontNeed,
+    NoReuse,
+}
+
+#[cfg(target_os = "linux")]
+impl File {
+    /// Allocates disk space for the given range of the file.
+    ///
+    /// Filesystems without support for `fallocate` get a `posix_fallocate`
+    /// fallback, which writes zeroes to the range. That only works with the
+    /// default options however.
+    pub fn allocate(&self, offset: u64, len: u64, opts: &AllocateOptions) -> io::Result<()> {
+        let mode = opts.mode();
+        match cvt_r(|| unsafe {
+            libc::fallocate64(self.0.raw(), mode, offset as off64_t, len as off64_t)
+        }) {
+            Ok(_) => return Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) && mode == 0 => {}
+            Err(e) => return Err(e),
+        }
+
+        // Unlike most calls `posix_fallocate` returns the error instead of
+        // setting errno.
+        loop {
+            match unsafe {
+                libc::posix_fallocate64(self.0.raw(), offset as off64_t, len as off64_t)
+            } {
+                0 => return Ok(()),
+                libc::EINTR => {}
+                e => return Err(Error::from_raw_os_error(e)),
+            }
+        }
+    }
+
+    /// Announces how the given range of the file is going to be accessed. A
+    /// `len` of zero extends the range to the end of the file.
+    pub fn advise(&self, offset: u64, len: u64, advice: Advice) -> io::Result<()> {
+        let advice = match advice {
+            Advice::Normal => libc::POSIX_FADV_NORMAL,
+            Advice::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+            Advice::Random => libc::POSIX_FADV_RANDOM,
+            Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+            Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+            Advice::NoReuse => libc::POSIX_FADV_NOREUSE,
+        };
+        // Like `posix_fallocate`, this returns the error directly.
+        match unsafe {
+            libc::posix_fadvise64(self.0.raw(), offset as off64_t, len as off64_t, advice)
+        } {
+            0 => Ok(()),
+            e => Err(Error::from_raw_os_error(e)),
+        }
+    }
+
+    /// Starts reading the given range of the file into the page cache.
+    pub fn readahead(&self, offset: u64, len: usize) -> io::Result<()> {
+        cvt(unsafe { libc::readahead(self.0.raw(), offset as off64_t, len) })?;
+        Ok(())
+    }
+
+    /// Deallocates the given range of the file, which then reads back as
+    /// zeroes. The file size is left unchanged.
+    pub fn punch_hole(&self, offset: u64, len: u64) -> io::Result<()> {
+        cvt_r(|| unsafe {
+            libc::fallocate64(self.0.raw(),
+                              libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                              offset as off64_t,
+                              len as off64_t)
+        })?;
+        Ok(())
+    }
+
+    /// Moves the file position to the start of the next range containing data
+    /// at or after `offset`, returning `None` if there is no data past it.
+    pub fn seek_data(&self, offset: u64) -> io::Result<Option<u64>> {
+        self.seek_extent(offset, libc::SEEK_DATA)
+    }
+
+    /// Moves the file position to the start of the next hole at or after
+    /// `offset`. The end of the file counts as a hole, so this only returns
+    /// `None` if `offset` is past the end of the file.
+    pub fn seek_hole(&self, offset: u64) -> io::Result<Option<u64>> {
+        self.seek_extent(offset, libc::SEEK_HOLE)
+    }
+
+    fn seek_extent(&self, offset: u64, whence: c_int) -> io::Result<Option<u64>> {
+        match cvt(unsafe { lseek64(self.0.raw(), offset as off64_t, whence) }) {
+            Ok(n) => Ok(Some(n as u64)),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns an iterator over the data and hole ranges of the file, in
+    /// order. This moves the file position around.
+    ///
+    /// Filesystems that don't keep track of holes report the whole file as a
+    /// single data range.
+    pub fn extents(&self) -> io::Result<Extents> {
+        let end = FileT::file_attr(self)?.size();
+        Ok(self.extents_to(end))
+    }
+
+    // Like `extents`, but stops at `end` instead of the current size.
+    fn extents_to(&self, end: u64) -> Extents {
+        Extents { file: self, pos: 0, end: end }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ExtentKind {
+    Data,
+    Hole,
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Extent {
+    pub offset: u64,
+    pub len: u64,
+    pub kind: ExtentKind,
+}
+
+#[cfg(target_os = "linux")]
+pub struct Extents<'a> {
+    file: &'a File,
+    pos: u64,
+    end: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> Iterator for Extents<'a> {
+    type Item = io::Result<Extent>;
+
+    fn next(&mut self) -> Option<io::Result<Extent>> {
+        if self.pos >= self.end {
+            return None;
+        }
+
+        let data = match self.file.seek_data(self.pos) {
+            Ok(data) => data.unwrap_or(self.end),
+            Err(e) => {
+                self.pos = self.end;
+                return Some(Err(e));
+            }
+        };
+        if data > self.pos {
+            let extent = Extent {
+                offset: self.pos,
+                len: data.min(self.end) - self.pos,
+                kind: ExtentKind::Hole,
+            };
+            self.pos = data;
+            return Some(Ok(extent));
+        }
+
+        let hole = match self.file.seek_hole(self.pos) {
+            Ok(hole) => hole.unwrap_or(self.end),
+            Err(e) => {
+                self.pos = self.end;
+                return Some(Err(e));
+            }
+        };
+        let extent = Extent {
+            offset: self.pos,
+            len: hole.min(self.end) - self.pos,
+            kind: ExtentKind::Data,
+        };
+        self.pos = hole;
+        Some(Ok(extent))
+    }
+}
+
+/// Like `copy`, but leaves holes in the source file as holes in the
+/// destination instead of filling them with zeroes.
+#[cfg(target_os = "linux")]
+pub fn copy_sparse(from: &Path, to: &Path) -> io::Result<u64> {
+    let mut opts = OpenOptions::new();
+    opts.read(true);
+    let reader = File::open(from, &opts)?;
+    let attr = reader.file_attr()?;
+    if !attr.file_type().is_file() {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "the source path is not an existing regular file"))
+    }
 
-    fn into_fd(self) -> FileDesc { self.0 }
+    let mut opts = OpenOptions::new();
+    opts.write(true);
+    opts.create(true);
+    opts.truncate(true);
+    let writer = File::open(to, &opts)?;
+
+    // Setting the size up front makes trailing holes come out right, as
+    // nothing is written to them.
+    writer.truncate(attr.size())?;
+
+    let mut size = attr.size();
+    let mut buf = vec![0; 64 * 1024];
+    // Stick to the size we truncated to, even if the source grows meanwhile.
+    'extents: for extent in reader.extents_to(size) {
+        let extent = extent?;
+        if extent.kind == ExtentKind::Hole {
+            continue;
+        }
+        let mut offset = extent.offset;
+        let end = extent.offset + extent.len;
+        while offset < end {
+            let want = (end - offset).min(buf.len() as u64) as usize;
+            let n = reader.read_at(&mut buf[..want], offset)?;
+            if n == 0 {
+                // The file shrank since we looked at its size, so the copy
+                // has to shrink along with it.
+                size = offset;
+                writer.truncate(size)?;
+                break 'extents;
+            }
+            let mut written = 0;
+            while written < n {
+                match writer.write_at(&buf[written..n], offset + written as u64)? {
+                    0 => return Err(Error::new(ErrorKind::WriteZero,
+                                               "failed to write whole buffer")),
+                    m => written += m,
+                }
+            }
+            offset += n as u64;
+        }
+    }
+
+    writer.set_permissions(attr.perm())?;
+    Ok(size)
 }
 
 impl traits::DirBuilder<Std> for DirBuilder {
     fn new() -> DirBuilder {
-        DirBuilder { mode: 0o777 }
+        DirBuilder { mode: 0o777, recursive: false }
     }
 
     fn mkdir(&self, p: &Path) -> io::Result<()> {
+        if self.recursive {
+            self.mkdir_all(p)
+        } else {
+            self.mkdir_one(p)
+        }
+    }
+}
+
+impl DirBuilder {
+    pub fn set_mode(&mut self, mode: u32) {
+        self.mode = mode as mode_t;
+    }
+
+    /// Makes `mkdir` create all missing parent directories as well, like
+    /// `mkdir -p`. Every created level gets the mode set with `set_mode`.
+    pub fn set_recursive(&mut self, recursive: bool) {
+        self.recursive = recursive;
+    }
+
+    fn mkdir_one(&self, p: &Path) -> io::Result<()> {
         let p = cstr(p)?;
         cvt(unsafe { libc::mkdir(p.as_ptr(), self.mode) })?;
         Ok(())
     }
+
+    fn mkdir_all(&self, p: &Path) -> io::Result<()> {
+        if p.as_os_str().is_empty() {
+            return Ok(())
+        }
+
+        // Optimistically try the directory itself first, so the common case
+        // of an existing parent only costs a single syscall. If it already
+        // exists, possibly because someone else created it concurrently, it's
+        // fine as long as it is a directory.
+        match self.mkdir_one(p) {
+            Ok(()) => return Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+            Err(_) if is_dir(p) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        match p.parent() {
+            Some(parent) => self.mkdir_all(parent)?,
+            None => return Err(io::Error::new(ErrorKind::Other,
+                                              "failed to create whole tree")),
+        }
+        match self.mkdir_one(p) {
+            Ok(()) => Ok(()),
+            Err(_) if is_dir(p) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
 }
 
-impl DirBuilder {
+fn is_dir(p: &Path) -> bool {
+    stat(p).map(|m| m.file_type().is_dir()).unwrap_or(false)
+}
+
+impl NodeBuilder {
+    pub fn new() -> NodeBuilder {
+        NodeBuilder { mode: 0o666 }
+    }
+
     pub fn set_mode(&mut self, mode: u32) {
-        self.mode = mode as mode_t;
+        self.mode = mode as mode_t & !libc::S_IFMT;
     }
+
+    pub fn mkfifo(&self, p: &Path) -> io::Result<()> {
+        let p = cstr(p)?;
+        cvt(unsafe { libc::mkfifo(p.as_ptr(), self.mode) })?;
+        Ok(())
+    }
+
+    /// Creates a node of the given `kind`, one of `S_IFREG`, `S_IFCHR`,
+    /// `S_IFBLK`, `S_IFIFO` or `S_IFSOCK`. `dev` is only used for device
+    /// nodes, see `makedev`.
+    pub fn mknod(&self, p: &Path, kind: mode_t, dev: u64) -> io::Result<()> {
+        let p = cstr(p)?;
+        cvt(unsafe {
+            libc::mknod(p.as_ptr(), self.mode | (kind & libc::S_IFMT), dev as libc::dev_t)
+        })?;
+        Ok(())
+    }
+}
+
+pub fn makedev(major: u32, minor: u32) -> u64 {
+    libc::makedev(major as _, minor as _) as u64
 }
 
 fn cstr(path: &Path) -> io::Result<CString> {
@@ -678,6 +1515,21 @@ pub fn readdir(p: &Path) -> io::Result<ReadDir> {
     }
 }
 
+/// Reads the directory open as `fd`. The entries' paths are joined onto
+/// `root`, which should be the path of the directory.
+pub fn readdir_fd(fd: FileDesc, root: PathBuf) -> io::Result<ReadDir> {
+    unsafe {
+        let ptr = libc::fdopendir(fd.raw());
+        if ptr.is_null() {
+            Err(Error::last_os_error())
+        } else {
+            // The stream owns the descriptor from now on.
+            fd.into_raw();
+            Ok(ReadDir { dirp: Dir(ptr), root: Arc::new(root) })
+        }
+    }
+}
+
 pub fn unlink(p: &Path) -> io::Result<()> {
     let p = cstr(p)?;
     cvt(unsafe { libc::unlink(p.as_ptr()) })?;
@@ -726,21 +1578,25 @@ fn remove_dir_all_recursive(path: &Path) -> io::Result<()> {
 
 pub fn readlink(p: &Path) -> io::Result<PathBuf> {
     let c_path = cstr(p)?;
-    let p = c_path.as_ptr();
+    let mut buf = readlink_at(libc::AT_FDCWD, &c_path)?;
+    buf.shrink_to_fit();
+    Ok(PathBuf::from(OsString::from_inner(os_str::Buf::from_inner(buf))))
+}
 
+/// Reads the target of the symlink `name` in the directory `dir`, which may
+/// be `AT_FDCWD`.
+pub fn readlink_at(dir: c_int, name: &CStr) -> io::Result<Vec<u8>> {
     let mut buf = Vec::with_capacity(256);
 
     loop {
         let buf_read = cvt(unsafe {
-            libc::readlink(p, buf.as_mut_ptr() as *mut _, buf.capacity())
+            libc::readlinkat(dir, name.as_ptr(), buf.as_mut_ptr() as *mut _, buf.capacity())
         })? as usize;
 
         unsafe { buf.set_len(buf_read); }
 
         if buf_read != buf.capacity() {
-            buf.shrink_to_fit();
-
-            return Ok(PathBuf::from(OsString::from_inner(os_str::Buf::from_inner(buf))));
+            return Ok(buf);
         }
 
         // Trigger the internal buffer resizing logic of `Vec` by requiring
@@ -764,22 +1620,292 @@ pub fn link(src: &Path, dst: &Path) -> io::Result<()> {
     Ok(())
 }
 
+pub fn statvfs(p: &Path) -> io::Result<FsStats> {
+    let p = cstr(p)?;
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    cvt_r(|| unsafe { libc::statvfs(p.as_ptr(), &mut stat) })?;
+
+    #[cfg(target_os = "linux")]
+    {
+        let mut fs: libc::statfs = unsafe { mem::zeroed() };
+        cvt_r(|| unsafe { libc::statfs(p.as_ptr(), &mut fs) })?;
+        return Ok(FsStats { stat: stat, fs_type: fs.f_type as u64 });
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    Ok(FsStats { stat: stat })
+}
+
+pub fn set_times(p: &Path, accessed: FileTime, modified: FileTime) -> io::Result<()> {
+    utimensat(p, accessed, modified, 0)
+}
+
+/// Like `set_times`, but changes the timestamps of a symlink itself rather
+/// than those of the file it points to.
+pub fn lset_times(p: &Path, accessed: FileTime, modified: FileTime) -> io::Result<()> {
+    utimensat(p, accessed, modified, libc::AT_SYMLINK_NOFOLLOW)
+}
+
+pub fn chown(p: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    let p = cstr(p)?;
+    let (uid, gid) = owner_ids(uid, gid);
+    cvt(unsafe { libc::chown(p.as_ptr(), uid, gid) })?;
+    Ok(())
+}
+
+pub fn lchown(p: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    let p = cstr(p)?;
+    let (uid, gid) = owner_ids(uid, gid);
+    cvt(unsafe { libc::lchown(p.as_ptr(), uid, gid) })?;
+    Ok(())
+}
+
+/// Changes the owner of `p`, resolved relative to the directory `dir`.
+pub fn fchownat(dir: &File, p: &Path, uid: Option<u32>, gid: Option<u32>,
+                follow_symlinks: bool) -> io::Result<()> {
+    let p = cstr(p)?;
+    let (uid, gid) = owner_ids(uid, gid);
+    let flags = if follow_symlinks { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+    cvt(unsafe { libc::fchownat(dir.0.raw(), p.as_ptr(), uid, gid, flags) })?;
+    Ok(())
+}
+
+// A uid or gid of -1 tells the `*chown` family to leave that id unchanged.
+fn owner_ids(uid: Option<u32>, gid: Option<u32>) -> (libc::uid_t, libc::gid_t) {
+    (uid.map(|id| id as libc::uid_t).unwrap_or(!0),
+     gid.map(|id| id as libc::gid_t).unwrap_or(!0))
+}
+
+fn utimensat(p: &Path, accessed: FileTime, modified: FileTime, flags: c_int) -> io::Result<()> {
+    let p = cstr(p)?;
+    let times = [accessed.to_timespec(), modified.to_timespec()];
+    cvt(unsafe {
+        libc::utimensat(libc::AT_FDCWD, p.as_ptr(), times.as_ptr(), flags)
+    })?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn getxattr(p: &Path, name: &OsStr) -> io::Result<Vec<u8>> {
+    let p = cstr(p)?;
+    let name = CString::new(name.as_bytes())?;
+    read_xattr(|buf, len| unsafe {
+        libc::getxattr(p.as_ptr(), name.as_ptr(), buf, len)
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lgetxattr(p: &Path, name: &OsStr) -> io::Result<Vec<u8>> {
+    let p = cstr(p)?;
+    let name = CString::new(name.as_bytes())?;
+    read_xattr(|buf, len| unsafe {
+        libc::lgetxattr(p.as_ptr(), name.as_ptr(), buf, len)
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn setxattr(p: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let p = cstr(p)?;
+    let name = CString::new(name.as_bytes())?;
+    cvt(unsafe {
+        libc::setxattr(p.as_ptr(), name.as_ptr(),
+                       value.as_ptr() as *const libc::c_void, value.len(), 0)
+    })?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lsetxattr(p: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+    let p = cstr(p)?;
+    let name = CString::new(name.as_bytes())?;
+    cvt(unsafe {
+        libc::lsetxattr(p.as_ptr(), name.as_ptr(),
+                        value.as_ptr() as *const libc::c_void, value.len(), 0)
+    })?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn listxattr(p: &Path) -> io::Result<Vec<OsString>> {
+    let p = cstr(p)?;
+    read_xattr(|buf, len| unsafe {
+        libc::listxattr(p.as_ptr(), buf as *mut libc::c_char, len)
+    }).map(split_xattr_names)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn llistxattr(p: &Path) -> io::Result<Vec<OsString>> {
+    let p = cstr(p)?;
+    read_xattr(|buf, len| unsafe {
+        libc::llistxattr(p.as_ptr(), buf as *mut libc::c_char, len)
+    }).map(split_xattr_names)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn removexattr(p: &Path, name: &OsStr) -> io::Result<()> {
+    let p = cstr(p)?;
+    let name = CString::new(name.as_bytes())?;
+    cvt(unsafe { libc::removexattr(p.as_ptr(), name.as_ptr()) })?;
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lremovexattr(p: &Path, name: &OsStr) -> io::Result<()> {
+    let p = cstr(p)?;
+    let name = CString::new(name.as_bytes())?;
+    cvt(unsafe { libc::lremovexattr(p.as_ptr(), name.as_ptr()) })?;
+    Ok(())
+}
+
+// Runs one of the `*getxattr`/`*listxattr` calls with a buffer that is grown
+// until the whole value fits.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn read_xattr<F>(mut f: F) -> io::Result<Vec<u8>>
+    where F: FnMut(*mut libc::c_void, libc::size_t) -> libc::ssize_t
+{
+    let mut buf: Vec<u8> = Vec::with_capacity(256);
+
+    loop {
+        match cvt(f(buf.as_mut_ptr() as *mut libc::c_void, buf.capacity())) {
+            Ok(n) => {
+                unsafe { buf.set_len(n as usize); }
+                buf.shrink_to_fit();
+                return Ok(buf);
+            }
+            Err(ref e) if e.raw_os_error() == Some(libc::ERANGE) => {}
+            Err(e) => return Err(e),
+        }
+
+        // Ask for the current size of the value and make room for it. The
+        // value may still grow before the next call, in which case we just
+        // go around again.
+        let size = cvt(f(ptr::null_mut(), 0))? as usize;
+        buf.reserve(size);
+    }
+}
+
+// Splits the NUL-terminated names returned by `*listxattr`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn split_xattr_names(buf: Vec<u8>) -> Vec<OsString> {
+    buf.split(|&b| b == 0)
+       .filter(|name| !name.is_empty())
+       .map(|name| OsStr::from_bytes(name).to_os_string())
+       .collect()
+}
+
 pub fn stat(p: &Path) -> io::Result<FileAttr> {
     let p = cstr(p)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(ret) = unsafe { try_statx(
+            libc::AT_FDCWD,
+            p.as_ptr(),
+            libc::AT_STATX_SYNC_AS_STAT,
+            libc::STATX_ALL | libc::STATX_MNT_ID,
+        ) } {
+            return ret;
+        }
+    }
+
     let mut stat: stat64 = unsafe { mem::zeroed() };
     cvt(unsafe {
         stat64(p.as_ptr(), &mut stat as *mut _ as *mut _)
     })?;
-    Ok(FileAttr { stat: stat })
+    Ok(FileAttr::from_stat64(stat))
 }
 
 pub fn lstat(p: &Path) -> io::Result<FileAttr> {
     let p = cstr(p)?;
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(ret) = unsafe { try_statx(
+            libc::AT_FDCWD,
+            p.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT,
+            libc::STATX_ALL | libc::STATX_MNT_ID,
+        ) } {
+            return ret;
+        }
+    }
+
     let mut stat: stat64 = unsafe { mem::zeroed() };
     cvt(unsafe {
         lstat64(p.as_ptr(), &mut stat as *mut _ as *mut _)
     })?;
-    Ok(FileAttr { stat: stat })
+    Ok(FileAttr::from_stat64(stat))
+}
+
+/// Like `lstat`, but looks `name` up in the directory `dir`.
+pub fn lstat_at(dir: &FileDesc, name: &CStr) -> io::Result<FileAttr> {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(ret) = unsafe { try_statx(
+            dir.raw(),
+            name.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_STATX_SYNC_AS_STAT,
+            libc::STATX_ALL | libc::STATX_MNT_ID,
+        ) } {
+            return ret;
+        }
+    }
+
+    let mut stat: stat64 = unsafe { mem::zeroed() };
+    cvt(unsafe {
+        fstatat64(dir.raw(), name.as_ptr(), &mut stat as *mut _ as *mut _,
+                  libc::AT_SYMLINK_NOFOLLOW)
+    })?;
+    Ok(FileAttr::from_stat64(stat))
+}
+
+// Fetches the attributes through `statx` if it's available, returning `None`
+// if the caller should fall back to the `stat64` family instead.
+#[cfg(target_os = "linux")]
+unsafe fn try_statx(
+    fd: c_int,
+    path: *const libc::c_char,
+    flags: c_int,
+    mask: u32,
+) -> Option<io::Result<FileAttr>> {
+    weak! { fn statx(c_int, *const libc::c_char, c_int, libc::c_uint, *mut libc::statx) -> c_int }
+    static UNAVAILABLE: AtomicBool = ATOMIC_BOOL_INIT;
+
+    if UNAVAILABLE.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    // `statx` was added in Linux 4.11 and glibc 2.28. If glibc doesn't
+    // provide a wrapper we issue the syscall ourselves, and if the kernel
+    // doesn't know about it (or a seccomp filter hides it) we remember that
+    // and stick to `stat64` from then on.
+    let call = |fd, path, flags, mask, buf: *mut libc::statx| match statx.get() {
+        Some(statx) => cvt(statx(fd, path, flags, mask, buf)),
+        None => cvt(libc::syscall(libc::SYS_statx, fd, path, flags, mask, buf) as c_int),
+    };
+    let mut buf: libc::statx = mem::zeroed();
+    match call(fd, path, flags, mask, &mut buf) {
+        Ok(_) => {}
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+            UNAVAILABLE.store(true, Ordering::Relaxed);
+            return None;
+        }
+        Err(e) => {
+            // EPERM may just be about this particular path. A seccomp filter
+            // rejects every call, while the real `statx` gets as far as
+            // complaining about the null pointers here with EFAULT.
+            if e.raw_os_error() == Some(libc::EPERM) {
+                let probe = call(0, ptr::null(), 0, libc::STATX_ALL, ptr::null_mut());
+                if probe.err().and_then(|e| e.raw_os_error()) != Some(libc::EFAULT) {
+                    UNAVAILABLE.store(true, Ordering::Relaxed);
+                    return None;
+                }
+            }
+            return Some(Err(e));
+        }
+    }
+
+    Some(Ok(FileAttr::from_statx(&buf)))
 }
 
 pub fn canonicalize(p: &Path) -> io::Result<PathBuf> {
@@ -796,6 +1922,116 @@ pub fn canonicalize(p: &Path) -> io::Result<PathBuf> {
     Ok(PathBuf::from(OsString::from_inner(os_str::Buf::from_inner(buf))))
 }
 
+/// How many symlinks a path resolved by hand may go through before giving up
+/// with ELOOP, the same limit Linux applies.
+pub const MAX_SYMLINKS: usize = 40;
+
+/// Like `canonicalize`, but resolves symlinks itself instead of relying on
+/// `realpath`, so it isn't limited to `PATH_MAX`. Components that don't exist
+/// are appended as they are, after normalizing away `.` and `..`.
+pub fn resolve(p: &Path) -> io::Result<PathBuf> {
+    let mut pending = Vec::new();
+    push_components(&mut pending, p.as_os_str().as_bytes());
+    if !p.is_absolute() {
+        push_components(&mut pending, os::getcwd()?.as_os_str().as_bytes());
+    }
+
+    let mut resolved = vec![b'/'];
+    let mut symlinks = 0;
+    let mut missing = false;
+    while let Some(component) = pending.pop() {
+        if component == b"." {
+            continue;
+        }
+        if component == b".." {
+            // Everything in `resolved` is a real directory, so going up is
+            // just a matter of dropping the last component.
+            pop_component(&mut resolved);
+            continue;
+        }
+
+        let len = resolved.len();
+        if len > 1 {
+            resolved.push(b'/');
+        }
+        resolved.extend_from_slice(&component);
+
+        // Once a component is missing, there is nothing left to resolve.
+        if missing {
+            continue;
+        }
+
+        let path = bytes_to_path(resolved.clone());
+        match lstat(&path) {
+            Ok(ref attr) if attr.file_type().is_symlink() => {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = readlink(&path)?;
+                resolved.truncate(len);
+                if target.is_absolute() {
+                    resolved.truncate(1);
+                }
+                push_components(&mut pending, target.as_os_str().as_bytes());
+            }
+            Ok(_) => {}
+            Err(ref e) if e.kind() == ErrorKind::NotFound => missing = true,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(bytes_to_path(resolved))
+}
+
+/// Makes `p` absolute by joining it onto the current directory and
+/// normalizing away `.` and `..` components, without looking at the
+/// filesystem. This means that `..` following a symlink can end up somewhere
+/// different than it would when resolved by the kernel.
+pub fn absolute(p: &Path) -> io::Result<PathBuf> {
+    let mut pending = Vec::new();
+    push_components(&mut pending, p.as_os_str().as_bytes());
+    if !p.is_absolute() {
+        push_components(&mut pending, os::getcwd()?.as_os_str().as_bytes());
+    }
+
+    let mut normalized = vec![b'/'];
+    while let Some(component) = pending.pop() {
+        if component == b"." {
+            continue;
+        }
+        if component == b".." {
+            pop_component(&mut normalized);
+            continue;
+        }
+        if normalized.len() > 1 {
+            normalized.push(b'/');
+        }
+        normalized.extend_from_slice(&component);
+    }
+
+    Ok(bytes_to_path(normalized))
+}
+
+// Pushes the components of `path` onto `stack` so that the first component
+// is popped first.
+pub fn push_components(stack: &mut Vec<Vec<u8>>, path: &[u8]) {
+    stack.extend(path.split(|&b| b == b'/')
+                     .filter(|c| !c.is_empty())
+                     .rev()
+                     .map(|c| c.to_vec()));
+}
+
+// Removes the last component of an absolute path, leaving the root alone.
+fn pop_component(path: &mut Vec<u8>) {
+    let start = path.iter().rposition(|&b| b == b'/').unwrap_or(0);
+    path.truncate(if start == 0 { 1 } else { start });
+}
+
+fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from_inner(os_str::Buf::from_inner(bytes)))
+}
+
 pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
     use ap::fs::{File, set_permissions};
     if !from.is_file() {
@@ -0,0 +1,113 @@
+//! File change notification through inotify.
+
+use ap::prelude::*;
+use ap::sys_common::FromInner;
+
+use ffi::{CString, OsString};
+use fd::FileDesc;
+use io;
+use libc::{self, c_int};
+use core::mem;
+use core::ptr;
+use os_str;
+use path::Path;
+use cvt;
+
+/// Identifies a watch added with `Watcher::add_watch`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct WatchDescriptor(c_int);
+
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub wd: WatchDescriptor,
+    /// The `IN_*` flags describing what happened.
+    pub mask: u32,
+    /// Connects the `IN_MOVED_FROM` and `IN_MOVED_TO` halves of a rename.
+    pub cookie: u32,
+    /// The name of the affected entry, for events on the contents of a
+    /// watched directory.
+    pub name: Option<OsString>,
+}
+
+/// An inotify instance. Its descriptor is nonblocking, so `read_events` only
+/// returns what's already queued; use `fd` to poll for more.
+#[derive(Debug)]
+pub struct Watcher {
+    fd: FileDesc,
+}
+
+impl Watcher {
+    pub fn new() -> io::Result<Watcher> {
+        let fd = cvt(unsafe {
+            libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK)
+        })?;
+        Ok(Watcher { fd: FileDesc::new(fd) })
+    }
+
+    /// Starts watching `p` for the events in `mask`, a combination of the
+    /// `libc::IN_*` flags. Watching the same path again replaces the mask
+    /// and returns the same descriptor.
+    pub fn add_watch(&self, p: &Path, mask: u32) -> io::Result<WatchDescriptor> {
+        let p = CString::new(p.as_os_str().as_bytes())?;
+        let wd = cvt(unsafe {
+            libc::inotify_add_watch(self.fd.raw(), p.as_ptr(), mask)
+        })?;
+        Ok(WatchDescriptor(wd))
+    }
+
+    pub fn rm_watch(&self, wd: WatchDescriptor) -> io::Result<()> {
+        cvt(unsafe { libc::inotify_rm_watch(self.fd.raw(), wd.0) })?;
+        Ok(())
+    }
+
+    /// Reads all queued events, returning an empty list if there are none.
+    pub fn read_events(&self) -> io::Result<Vec<Event>> {
+        // Large enough for at least one event with a name of NAME_MAX bytes.
+        let mut buf = [0u8; 8192];
+
+        let mut events = Vec::new();
+        loop {
+            let n = match self.fd.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.raw_os_error() == Some(libc::EAGAIN) ||
+                              e.raw_os_error() == Some(libc::EWOULDBLOCK) => break,
+                Err(e) => return Err(e),
+            };
+            parse_events(&buf[..n], &mut events);
+        }
+        Ok(events)
+    }
+
+    pub fn fd(&self) -> &FileDesc { &self.fd }
+    pub fn into_fd(self) -> FileDesc { self.fd }
+}
+
+fn parse_events(mut buf: &[u8], events: &mut Vec<Event>) {
+    let header = mem::size_of::<libc::inotify_event>();
+    while buf.len() >= header {
+        // The kernel pads names so every header is suitably aligned, but
+        // there's no harm in not relying on that.
+        let event: libc::inotify_event = unsafe {
+            ptr::read_unaligned(buf.as_ptr() as *const libc::inotify_event)
+        };
+        let len = event.len as usize;
+        let name = &buf[header..header + len];
+        // The name is NUL padded up to `len`.
+        let name = match name.iter().position(|&b| b == 0) {
+            Some(end) => &name[..end],
+            None => name,
+        };
+        events.push(Event {
+            wd: WatchDescriptor(event.wd),
+            mask: event.mask,
+            cookie: event.cookie,
+            name: if name.is_empty() {
+                None
+            } else {
+                Some(OsString::from_inner(os_str::Buf::from_inner(name.to_vec())))
+            },
+        });
+        buf = &buf[header + len..];
+    }
+}
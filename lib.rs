@@ -31,9 +31,18 @@ mod path;
 mod time;
 mod fs;
 mod stdio;
+mod rand;
+pub mod mmap;
+#[cfg(target_os = "linux")]
+pub mod inotify;
+pub mod root;
+pub mod poll;
+#[cfg(target_os = "linux")]
+pub mod uring;
 
 // mod stack_overflow;
-// mod rand;
+
+pub use os::unix;
 
 use ap::traits;
 use ap::io::ErrorKind;
@@ -0,0 +1,218 @@
+//! Memory-mapped files and anonymous mappings.
+
+use libc::{self, c_int, c_void};
+use core::ops::{Deref, DerefMut};
+use core::ptr;
+use core::slice;
+use fs::File;
+use io;
+use os;
+use cvt;
+
+#[cfg(target_os = "linux")]
+use libc::{mmap64, off64_t};
+#[cfg(not(target_os = "linux"))]
+use libc::{mmap as mmap64, off_t as off64_t};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Advice {
+    Normal,
+    Random,
+    Sequential,
+    WillNeed,
+    DontNeed,
+}
+
+// The mapping itself. `ptr` and `len` describe the range that was asked for,
+// which may start up to a page after the start of the actual mapping since
+// `mmap` needs a page aligned file offset.
+struct MmapInner {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+unsafe impl Send for MmapInner {}
+unsafe impl Sync for MmapInner {}
+
+impl MmapInner {
+    fn new(len: usize, prot: c_int, flags: c_int, fd: c_int, offset: u64) -> io::Result<MmapInner> {
+        if len == 0 {
+            // `mmap` refuses empty mappings, but an empty slice doesn't need
+            // to point anywhere. This also keeps us from mapping the bytes
+            // before an unaligned offset, which `Drop` wouldn't unmap.
+            return Ok(MmapInner { ptr: ptr::null_mut(), len: 0 });
+        }
+
+        let alignment = (offset % os::page_size() as u64) as usize;
+        let aligned_offset = offset - alignment as u64;
+        let aligned_len = match len.checked_add(alignment) {
+            Some(aligned_len) => aligned_len,
+            None => return Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        };
+
+        unsafe {
+            let ptr = mmap64(ptr::null_mut(),
+                             aligned_len,
+                             prot,
+                             flags,
+                             fd,
+                             aligned_offset as off64_t);
+            if ptr == libc::MAP_FAILED {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(MmapInner {
+                    ptr: (ptr as *mut u8).offset(alignment as isize) as *mut c_void,
+                    len: len,
+                })
+            }
+        }
+    }
+
+    // The page aligned start and length of the whole mapping.
+    fn aligned(&self) -> (*mut c_void, usize) {
+        let alignment = self.ptr as usize % os::page_size();
+        ((self.ptr as usize - alignment) as *mut c_void, self.len + alignment)
+    }
+
+    fn msync(&self, flags: c_int) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let (ptr, len) = self.aligned();
+        cvt(unsafe { libc::msync(ptr, len, flags) })?;
+        Ok(())
+    }
+
+    fn advise(&self, advice: Advice) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let advice = match advice {
+            Advice::Normal => libc::MADV_NORMAL,
+            Advice::Random => libc::MADV_RANDOM,
+            Advice::Sequential => libc::MADV_SEQUENTIAL,
+            Advice::WillNeed => libc::MADV_WILLNEED,
+            Advice::DontNeed => libc::MADV_DONTNEED,
+        };
+        let (ptr, len) = self.aligned();
+        cvt(unsafe { libc::madvise(ptr, len, advice) })?;
+        Ok(())
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        if self.len == 0 {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.ptr as *mut u8, self.len) }
+    }
+}
+
+impl Drop for MmapInner {
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        let (ptr, len) = self.aligned();
+        let r = unsafe { libc::munmap(ptr, len) };
+        debug_assert_eq!(r, 0);
+    }
+}
+
+/// A read-only mapping of a file.
+///
+/// The contents may change underneath if the file is modified, be it through
+/// this process or another one.
+pub struct Mmap(MmapInner);
+
+impl Mmap {
+    /// Maps `len` bytes of `file`, starting at `offset`, which doesn't have to
+    /// be page aligned.
+    pub unsafe fn map(file: &File, offset: u64, len: usize) -> io::Result<Mmap> {
+        MmapInner::new(len, libc::PROT_READ, libc::MAP_SHARED, file.fd().raw(), offset)
+            .map(Mmap)
+    }
+
+    pub fn advise(&self, advice: Advice) -> io::Result<()> {
+        self.0.advise(advice)
+    }
+}
+
+impl Deref for Mmap {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+/// A writable mapping, either of a file or of anonymous memory.
+pub struct MmapMut(MmapInner);
+
+impl MmapMut {
+    /// Maps `len` bytes of `file`, starting at `offset`. Writes end up in the
+    /// file and are visible to other mappings of it.
+    pub unsafe fn map_mut(file: &File, offset: u64, len: usize) -> io::Result<MmapMut> {
+        MmapInner::new(len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED,
+                       file.fd().raw(),
+                       offset)
+            .map(MmapMut)
+    }
+
+    /// Maps `len` bytes of `file`, starting at `offset`, copy-on-write. Writes
+    /// only affect this mapping and never reach the file.
+    pub unsafe fn map_copy(file: &File, offset: u64, len: usize) -> io::Result<MmapMut> {
+        MmapInner::new(len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE,
+                       file.fd().raw(),
+                       offset)
+            .map(MmapMut)
+    }
+
+    /// Maps `len` bytes of zeroed memory that isn't backed by any file.
+    pub fn map_anon(len: usize) -> io::Result<MmapMut> {
+        MmapInner::new(len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANON,
+                       -1,
+                       0)
+            .map(MmapMut)
+    }
+
+    /// Writes modified pages back to the file and waits for that to finish.
+    pub fn flush(&self) -> io::Result<()> {
+        self.0.msync(libc::MS_SYNC)
+    }
+
+    /// Schedules modified pages to be written back without waiting for it.
+    pub fn flush_async(&self) -> io::Result<()> {
+        self.0.msync(libc::MS_ASYNC)
+    }
+
+    pub fn advise(&self, advice: Advice) -> io::Result<()> {
+        self.0.advise(advice)
+    }
+}
+
+impl Deref for MmapMut {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+impl DerefMut for MmapMut {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut_slice()
+    }
+}
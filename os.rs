@@ -36,6 +36,143 @@ use ap::sys_common::mutex::Mutex;
 // use sys::fd;
 // use vec;
 
+/// Unix-specific extensions, in the manner of `std::os::unix`.
+pub mod unix {
+    pub mod io {
+        //! Access to the raw file descriptors underlying IO objects.
+
+        use ap::sys_common::{AsInner, FromInner, IntoInner};
+        use libc;
+
+        use fd::{BorrowedFd, FileDesc};
+        use fs;
+        use pipe;
+        use stdio;
+
+        pub type RawFd = libc::c_int;
+
+        pub trait AsRawFd {
+            /// Extracts the raw file descriptor, which stays owned by `self`.
+            fn as_raw_fd(&self) -> RawFd;
+        }
+
+        pub trait FromRawFd {
+            /// Takes ownership of the raw file descriptor `fd`, which is
+            /// closed when the returned object is dropped.
+            ///
+            /// This is unsafe as nothing guarantees that `fd` is open and not
+            /// owned by anything else.
+            unsafe fn from_raw_fd(fd: RawFd) -> Self;
+        }
+
+        pub trait IntoRawFd {
+            /// Gives up ownership of the file descriptor, which the caller is
+            /// now responsible for closing.
+            fn into_raw_fd(self) -> RawFd;
+        }
+
+        impl AsRawFd for FileDesc {
+            fn as_raw_fd(&self) -> RawFd { self.raw() }
+        }
+
+        impl FromRawFd for FileDesc {
+            unsafe fn from_raw_fd(fd: RawFd) -> FileDesc { FileDesc::new(fd) }
+        }
+
+        impl IntoRawFd for FileDesc {
+            fn into_raw_fd(self) -> RawFd { self.into_raw() }
+        }
+
+        impl<'a> AsRawFd for BorrowedFd<'a> {
+            fn as_raw_fd(&self) -> RawFd { self.raw() }
+        }
+
+        impl AsRawFd for fs::File {
+            fn as_raw_fd(&self) -> RawFd { self.fd().raw() }
+        }
+
+        impl FromRawFd for fs::File {
+            unsafe fn from_raw_fd(fd: RawFd) -> fs::File { fs::File::from_inner(fd) }
+        }
+
+        impl IntoRawFd for fs::File {
+            fn into_raw_fd(self) -> RawFd { self.into_fd().into_raw() }
+        }
+
+        impl AsRawFd for pipe::AnonPipe {
+            fn as_raw_fd(&self) -> RawFd { self.as_inner().raw() }
+        }
+
+        impl FromRawFd for pipe::AnonPipe {
+            unsafe fn from_raw_fd(fd: RawFd) -> pipe::AnonPipe {
+                pipe::AnonPipe::from_inner(FileDesc::new(fd))
+            }
+        }
+
+        impl IntoRawFd for pipe::AnonPipe {
+            fn into_raw_fd(self) -> RawFd { self.into_inner().into_raw() }
+        }
+
+        impl AsRawFd for stdio::Stdin {
+            fn as_raw_fd(&self) -> RawFd { libc::STDIN_FILENO }
+        }
+
+        impl AsRawFd for stdio::Stdout {
+            fn as_raw_fd(&self) -> RawFd { libc::STDOUT_FILENO }
+        }
+
+        impl AsRawFd for stdio::Stderr {
+            fn as_raw_fd(&self) -> RawFd { libc::STDERR_FILENO }
+        }
+    }
+
+    pub mod ffi {
+        //! Conversions between `OsStr`/`OsString` and raw bytes.
+
+        use ap::prelude::*;
+        use ap::sys_common::{AsInner, FromInner, IntoInner};
+
+        use ffi::{OsStr, OsString};
+        use os_str::Buf;
+
+        pub trait OsStrExt {
+            fn from_bytes(slice: &[u8]) -> &Self;
+            fn as_bytes(&self) -> &[u8];
+        }
+
+        impl OsStrExt for OsStr {
+            fn from_bytes(slice: &[u8]) -> &OsStr {
+                // The inherent constructor, which goes through `os_str::Slice`.
+                <OsStr>::from_bytes(slice)
+            }
+
+            fn as_bytes(&self) -> &[u8] {
+                &self.as_inner().inner
+            }
+        }
+
+        pub trait OsStringExt {
+            fn from_vec(vec: Vec<u8>) -> Self;
+            fn into_vec(self) -> Vec<u8>;
+        }
+
+        impl OsStringExt for OsString {
+            fn from_vec(vec: Vec<u8>) -> OsString {
+                OsString::from_inner(Buf { inner: vec })
+            }
+
+            fn into_vec(self) -> Vec<u8> {
+                self.into_inner().inner
+            }
+        }
+    }
+
+    pub mod prelude {
+        pub use super::io::{RawFd, AsRawFd, FromRawFd, IntoRawFd};
+        pub use super::ffi::{OsStrExt, OsStringExt};
+    }
+}
+
 const TMPBUF_SZ: usize = 128;
 static ENV_LOCK: Mutex<Std> = Mutex::new();
 
@@ -110,31 +247,31 @@ pub fn error_string(errno: i32) -> String {
     }
 }
 
-// pub fn getcwd() -> io::Result<PathBuf> {
-//     let mut buf = Vec::with_capacity(512);
-//     loop {
-//         unsafe {
-//             let ptr = buf.as_mut_ptr() as *mut libc::c_char;
-//             if !libc::getcwd(ptr, buf.capacity()).is_null() {
-//                 let len = CStr::from_ptr(buf.as_ptr() as *const libc::c_char).to_bytes().len();
-//                 buf.set_len(len);
-//                 buf.shrink_to_fit();
-//                 return Ok(PathBuf::from(OsString::from_vec(buf)));
-//             } else {
-//                 let error = io::Error::last_os_error();
-//                 if error.raw_os_error() != Some(libc::ERANGE) {
-//                     return Err(error);
-//                 }
-//             }
-
-//             // Trigger the internal buffer resizing logic of `Vec` by requiring
-//             // more space than the current capacity.
-//             let cap = buf.capacity();
-//             buf.set_len(cap);
-//             buf.reserve(1);
-//         }
-//     }
-// }
+pub fn getcwd() -> io::Result<PathBuf> {
+    let mut buf = Vec::with_capacity(512);
+    loop {
+        unsafe {
+            let ptr = buf.as_mut_ptr() as *mut libc::c_char;
+            if !libc::getcwd(ptr, buf.capacity()).is_null() {
+                let len = CStr::from_ptr(buf.as_ptr() as *const libc::c_char).to_bytes().len();
+                buf.set_len(len);
+                buf.shrink_to_fit();
+                return Ok(PathBuf::from(OsString::from_vec(buf)));
+            } else {
+                let error = io::Error::last_os_error();
+                if error.raw_os_error() != Some(libc::ERANGE) {
+                    return Err(error);
+                }
+            }
+
+            // Trigger the internal buffer resizing logic of `Vec` by requiring
+            // more space than the current capacity.
+            let cap = buf.capacity();
+            buf.set_len(cap);
+            buf.reserve(1);
+        }
+    }
+}
 
 // pub fn chdir(p: &path::Path) -> io::Result<()> {
 //     let p: &OsStr = p.as_ref();
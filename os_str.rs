@@ -24,6 +24,7 @@ use alloc::arc::Arc;
 use ap::sys_common::{AsInner, IntoInner, FromInner};
 use ap::sys_common::bytestring::debug_fmt_bytestring;
 use std_unicode::lossy::Utf8Lossy;
+use memchr;
 
 #[derive(Clone, Hash)]
 pub struct Buf {
@@ -154,6 +155,110 @@ impl Slice {
     fn from_u8_slice(s: &[u8]) -> &Slice {
         unsafe { mem::transmute(s) }
     }
+
+    pub fn starts_with(&self, prefix: &[u8]) -> bool {
+        self.inner.starts_with(prefix)
+    }
+
+    pub fn ends_with(&self, suffix: &[u8]) -> bool {
+        self.inner.ends_with(suffix)
+    }
+
+    /// Returns the rest of the string after `prefix`, if it starts with it.
+    pub fn strip_prefix(&self, prefix: &[u8]) -> Option<&Slice> {
+        if self.starts_with(prefix) {
+            Some(Slice::from_u8_slice(&self.inner[prefix.len()..]))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the rest of the string before `suffix`, if it ends with it.
+    pub fn strip_suffix(&self, suffix: &[u8]) -> Option<&Slice> {
+        if self.ends_with(suffix) {
+            Some(Slice::from_u8_slice(&self.inner[..self.inner.len() - suffix.len()]))
+        } else {
+            None
+        }
+    }
+
+    /// Splits the string at every occurrence of `sep`.
+    pub fn split(&self, sep: u8) -> Split {
+        Split { rest: Some(&self.inner), sep: sep }
+    }
+
+    /// Returns the index of the first occurrence of `needle`.
+    pub fn find(&self, needle: u8) -> Option<usize> {
+        memchr::memchr(needle, &self.inner)
+    }
+
+    /// Returns the index of the last occurrence of `needle`.
+    pub fn rfind(&self, needle: u8) -> Option<usize> {
+        memchr::memrchr(needle, &self.inner)
+    }
+
+    /// Strips leading and trailing ASCII whitespace.
+    pub fn trim_ascii(&self) -> &Slice {
+        let start = self.inner.iter()
+                              .position(|b| !b.is_ascii_whitespace())
+                              .unwrap_or(self.inner.len());
+        let end = self.inner.iter()
+                            .rposition(|b| !b.is_ascii_whitespace())
+                            .map_or(start, |i| i + 1);
+        Slice::from_u8_slice(&self.inner[start..end])
+    }
+
+    pub fn to_ascii_lowercase(&self) -> Buf {
+        Buf { inner: self.inner.iter().map(|b| b.to_ascii_lowercase()).collect() }
+    }
+
+    pub fn to_ascii_uppercase(&self) -> Buf {
+        Buf { inner: self.inner.iter().map(|b| b.to_ascii_uppercase()).collect() }
+    }
+
+    pub fn eq_ignore_ascii_case(&self, other: &Slice) -> bool {
+        self.inner.len() == other.inner.len() &&
+            self.inner.iter().zip(other.inner.iter()).all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Buf {
+    pub fn make_ascii_lowercase(&mut self) {
+        for b in self.inner.iter_mut() {
+            b.make_ascii_lowercase();
+        }
+    }
+
+    pub fn make_ascii_uppercase(&mut self) {
+        for b in self.inner.iter_mut() {
+            b.make_ascii_uppercase();
+        }
+    }
+}
+
+/// An iterator over the parts of a `Slice` separated by a byte, see
+/// `Slice::split`.
+pub struct Split<'a> {
+    rest: Option<&'a [u8]>,
+    sep: u8,
+}
+
+impl<'a> Iterator for Split<'a> {
+    type Item = &'a Slice;
+
+    fn next(&mut self) -> Option<&'a Slice> {
+        let rest = self.rest?;
+        match memchr::memchr(self.sep, rest) {
+            Some(i) => {
+                self.rest = Some(&rest[i + 1..]);
+                Some(Slice::from_u8_slice(&rest[..i]))
+            }
+            None => {
+                self.rest = None;
+                Some(Slice::from_u8_slice(rest))
+            }
+        }
+    }
 }
 
 impl traits::OsStr<Std> for Slice {
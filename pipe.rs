@@ -9,12 +9,20 @@
 // except according to those terms.
 
 use ap::prelude::*;
+use ap::sys_common::{AsInner, FromInner, IntoInner};
 
 use io;
 use libc::{self, c_int};
+use core::cmp;
 use core::mem;
+use core::ptr;
+use core::time::Duration;
 use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
-use fd::FileDesc;
+use fd::{self, FileDesc};
+use ffi::CString;
+use path::Path;
+use time::Instant;
+use traits::Instant as InstantT;
 use {cvt, cvt_r};
 
 ////////////////////////////////////////////////////////////////////////////////
@@ -24,6 +32,50 @@ use {cvt, cvt_r};
 pub struct AnonPipe(FileDesc);
 
 pub fn anon_pipe() -> io::Result<(AnonPipe, AnonPipe)> {
+    PipeBuilder::new().create()
+}
+
+/// Creates pipes with non-default flags.
+#[derive(Clone, Debug)]
+pub struct PipeBuilder {
+    nonblocking: bool,
+    direct: bool,
+}
+
+impl PipeBuilder {
+    pub fn new() -> PipeBuilder {
+        PipeBuilder { nonblocking: false, direct: false }
+    }
+
+    /// Makes both ends nonblocking (`O_NONBLOCK`).
+    pub fn nonblocking(&mut self, nonblocking: bool) { self.nonblocking = nonblocking; }
+
+    /// Creates the pipe in packet mode (`O_DIRECT`, Linux 3.4 and later),
+    /// where every write is read back as a separate packet. This requires
+    /// `pipe2`, so it fails with EINVAL where that isn't available.
+    pub fn direct(&mut self, direct: bool) { self.direct = direct; }
+
+    /// Returns the reading and the writing end of a new pipe. Both are
+    /// CLOEXEC.
+    pub fn create(&self) -> io::Result<(AnonPipe, AnonPipe)> {
+        let mut flags = libc::O_CLOEXEC;
+        if self.nonblocking {
+            flags |= libc::O_NONBLOCK;
+        }
+        if self.direct {
+            flags |= o_direct()?;
+        }
+        pipe_with_flags(flags)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn o_direct() -> io::Result<c_int> { Ok(libc::O_DIRECT) }
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn o_direct() -> io::Result<c_int> { Err(io::Error::from_raw_os_error(libc::EINVAL)) }
+
+fn pipe_with_flags(flags: c_int) -> io::Result<(AnonPipe, AnonPipe)> {
     weak! { fn pipe2(*mut c_int, c_int) -> c_int }
     static INVALID: AtomicBool = ATOMIC_BOOL_INIT;
 
@@ -47,7 +99,7 @@ pub fn anon_pipe() -> io::Result<(AnonPipe, AnonPipe)> {
             // emulate on older kernels, so if you happen to be running on
             // an older kernel you may see `pipe2` as a symbol but still not
             // see the syscall.
-            match cvt(unsafe { pipe(fds.as_mut_ptr(), libc::O_CLOEXEC) }) {
+            match cvt(unsafe { pipe(fds.as_mut_ptr(), flags) }) {
                 Ok(_) => {
                     return Ok((AnonPipe(FileDesc::new(fds[0])),
                                AnonPipe(FileDesc::new(fds[1]))));
@@ -59,12 +111,22 @@ pub fn anon_pipe() -> io::Result<(AnonPipe, AnonPipe)> {
             }
         }
     }
+
+    // Without `pipe2` the flags have to be set one by one, which works for
+    // everything but `O_DIRECT`.
+    if flags & !(libc::O_CLOEXEC | libc::O_NONBLOCK) != 0 {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
     cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
 
     let fd0 = FileDesc::new(fds[0]);
     let fd1 = FileDesc::new(fds[1]);
     fd0.set_cloexec()?;
     fd1.set_cloexec()?;
+    if flags & libc::O_NONBLOCK != 0 {
+        fd0.set_nonblocking(true)?;
+        fd1.set_nonblocking(true)?;
+    }
     Ok((AnonPipe(fd0), AnonPipe(fd1)))
 }
 
@@ -77,56 +139,344 @@ impl AnonPipe {
         self.0.write(buf)
     }
 
+    pub fn read_vectored(&self, bufs: &mut [&mut [u8]]) -> io::Result<usize> {
+        self.0.read_vectored(bufs)
+    }
+
+    pub fn write_vectored(&self, bufs: &[&[u8]]) -> io::Result<usize> {
+        self.0.write_vectored(bufs)
+    }
+
     pub fn fd(&self) -> &FileDesc { &self.0 }
     pub fn into_fd(self) -> FileDesc { self.0 }
+
+    /// Returns the capacity of the pipe in bytes.
+    #[cfg(target_os = "linux")]
+    pub fn capacity(&self) -> io::Result<usize> {
+        let n = cvt(unsafe { libc::fcntl(self.0.raw(), libc::F_GETPIPE_SZ) })?;
+        Ok(n as usize)
+    }
+
+    /// Changes the capacity of the pipe, returning the new capacity. The
+    /// kernel rounds it up to a power of two number of pages, and limits
+    /// unprivileged users to /proc/sys/fs/pipe-max-size.
+    #[cfg(target_os = "linux")]
+    pub fn set_capacity(&self, capacity: usize) -> io::Result<usize> {
+        let capacity = cmp::min(capacity, c_int::max_value() as usize) as c_int;
+        let n = cvt(unsafe {
+            libc::fcntl(self.0.raw(), libc::F_SETPIPE_SZ, capacity)
+        })?;
+        Ok(n as usize)
+    }
+}
+
+/// Opens the reading end of the FIFO at `p`.
+///
+/// A blocking open waits for a writer to show up, a nonblocking one returns
+/// right away. Either way the pipe is blocking once it's open. Fails with
+/// EINVAL if `p` isn't a FIFO.
+pub fn open_fifo_reader(p: &Path, nonblocking: bool) -> io::Result<AnonPipe> {
+    open_fifo(p, libc::O_RDONLY, nonblocking)
+}
+
+/// Opens the writing end of the FIFO at `p`.
+///
+/// A blocking open waits for a reader to show up, a nonblocking one fails
+/// with ENXIO if there is none. Either way the pipe is blocking once it's
+/// open. Fails with EINVAL if `p` isn't a FIFO.
+pub fn open_fifo_writer(p: &Path, nonblocking: bool) -> io::Result<AnonPipe> {
+    open_fifo(p, libc::O_WRONLY, nonblocking)
+}
+
+fn open_fifo(p: &Path, access: c_int, nonblocking: bool) -> io::Result<AnonPipe> {
+    let p = CString::new(p.as_os_str().as_bytes())?;
+    // O_NOCTTY in case `p` turns out to be a terminal rather than a FIFO.
+    let mut flags = access | libc::O_CLOEXEC | libc::O_NOCTTY;
+    if nonblocking {
+        flags |= libc::O_NONBLOCK;
+    }
+    let fd = FileDesc::new(cvt_r(|| unsafe { libc::open(p.as_ptr(), flags) })?);
+
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    cvt(unsafe { libc::fstat(fd.raw(), &mut stat) })?;
+    if stat.st_mode & libc::S_IFMT != libc::S_IFIFO {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+
+    // O_NONBLOCK only matters for the open itself, after which the pipe
+    // behaves like any other.
+    if nonblocking {
+        fd.set_nonblocking(false)?;
+    }
+    Ok(AnonPipe(fd))
+}
+
+impl AsInner<FileDesc> for AnonPipe {
+    fn as_inner(&self) -> &FileDesc { &self.0 }
+}
+
+impl IntoInner<FileDesc> for AnonPipe {
+    fn into_inner(self) -> FileDesc { self.0 }
+}
+
+impl FromInner<FileDesc> for AnonPipe {
+    fn from_inner(fd: FileDesc) -> AnonPipe { AnonPipe(fd) }
 }
 
 pub fn read2(p1: AnonPipe,
              v1: &mut Vec<u8>,
              p2: AnonPipe,
              v2: &mut Vec<u8>) -> io::Result<()> {
-
-    // Set both pipes into nonblocking mode as we're gonna be reading from both
-    // in the `select` loop below, and we wouldn't want one to block the other!
     let p1 = p1.into_fd();
     let p2 = p2.into_fd();
-    p1.set_nonblocking(true)?;
-    p2.set_nonblocking(true)?;
-
-    let mut fds: [libc::pollfd; 2] = unsafe { mem::zeroed() };
-    fds[0].fd = p1.raw();
-    fds[0].events = libc::POLLIN;
-    fds[1].fd = p2.raw();
-    fds[1].events = libc::POLLIN;
+    read_many(&[&p1, &p2], None, |i, event| {
+        if let ReadEvent::Data(data) = event {
+            if i == 0 {
+                v1.extend_from_slice(data);
+            } else {
+                v2.extend_from_slice(data);
+            }
+        }
+    }).map(|_| ())
+}
+
+/// What happened to one of the sources passed to `read_many`.
+#[derive(Debug)]
+pub enum ReadEvent<'a> {
+    /// A chunk of data was read.
+    Data(&'a [u8]),
+    /// The source reached EOF and won't be read from anymore.
+    Eof,
+}
+
+/// Reads from all of `fds` at once, handing each chunk to `f` as soon as it
+/// arrives along with the index of the descriptor it came from.
+///
+/// Returns `Ok(true)` once every descriptor has reached EOF, or `Ok(false)`
+/// if `deadline` passed first. The descriptors are left in nonblocking mode.
+pub fn read_many<F>(fds: &[&FileDesc],
+                    deadline: Option<Instant>,
+                    mut f: F) -> io::Result<bool>
+    where F: FnMut(usize, ReadEvent)
+{
+    // Set all descriptors into nonblocking mode as we're gonna be reading
+    // from them in the `poll` loop below, and we wouldn't want one to block
+    // the others!
+    for fd in fds {
+        fd.set_nonblocking(true)?;
+    }
+
+    let mut pollfds: Vec<libc::pollfd> = fds.iter().map(|fd| libc::pollfd {
+        fd: fd.raw(),
+        events: libc::POLLIN,
+        revents: 0,
+    }).collect();
+    let mut open = fds.len();
+    let mut buf = vec![0; 64 * 1024];
+
+    while open > 0 {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Ok(false);
+                }
+                timeout_ms(deadline.sub_instant(&now))
+            }
+            None => -1,
+        };
+
+        // wait for any of the descriptors to become readable using `poll`,
+        // going round again on EINTR so the timeout is worked out afresh
+        match cvt(unsafe {
+            libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout)
+        }) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        // Only do a single read per descriptor and round, so a busy one can't
+        // starve the others. Descriptors that hit EOF are set to -1, which
+        // makes `poll` skip them.
+        for (i, pollfd) in pollfds.iter_mut().enumerate() {
+            if pollfd.fd < 0 || pollfd.revents == 0 {
+                continue;
+            }
+            match fds[i].read(&mut buf) {
+                Ok(0) => {
+                    pollfd.fd = -1;
+                    open -= 1;
+                    f(i, ReadEvent::Eof);
+                }
+                Ok(n) => f(i, ReadEvent::Data(&buf[..n])),
+                Err(ref e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) ||
+                              e.raw_os_error() == Some(libc::EAGAIN) ||
+                              e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(true)
+}
+
+/// Converts a timeout for `poll` and friends, rounding up so we don't wake up
+/// just before the deadline.
+pub fn timeout_ms(d: Duration) -> c_int {
+    let ms = d.as_secs()
+              .saturating_mul(1000)
+              .saturating_add((d.subsec_nanos() as u64 + 999_999) / 1_000_000);
+    cmp::min(ms, c_int::max_value() as u64) as c_int
+}
+
+////////////////////////////////////////////////////////////////////////////////
+// Zero-copy transfers
+////////////////////////////////////////////////////////////////////////////////
+
+// The default capacity of a pipe on Linux.
+#[cfg(target_os = "linux")]
+const PIPE_BUF_SIZE: usize = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+fn splice(from: &FileDesc, from_offset: Option<&mut u64>,
+          to: &FileDesc, to_offset: Option<&mut u64>,
+          len: usize) -> io::Result<usize> {
+    let mut from_off = from_offset.as_ref().map(|o| **o as libc::loff_t);
+    let mut to_off = to_offset.as_ref().map(|o| **o as libc::loff_t);
+    let n = cvt_r(|| unsafe {
+        libc::splice(from.raw(),
+                     from_off.as_mut().map_or(ptr::null_mut(), |o| o as *mut _),
+                     to.raw(),
+                     to_off.as_mut().map_or(ptr::null_mut(), |o| o as *mut _),
+                     len,
+                     libc::SPLICE_F_MOVE)
+    })? as usize;
+    // The kernel advances the offsets it was given instead of the file
+    // positions, so pass that on.
+    if let (Some(offset), Some(off)) = (from_offset, from_off) {
+        *offset = off as u64;
+    }
+    if let (Some(offset), Some(off)) = (to_offset, to_off) {
+        *offset = off as u64;
+    }
+    Ok(n)
+}
+
+/// Moves up to `len` bytes from `from` into the pipe `to` without copying
+/// them through userspace. If `offset` is given, reading starts there and it
+/// is advanced instead of the file position of `from`.
+#[cfg(target_os = "linux")]
+pub fn splice_to_pipe(from: &FileDesc, offset: Option<&mut u64>,
+                      to: &AnonPipe, len: usize) -> io::Result<usize> {
+    splice(from, offset, &to.0, None, len)
+}
+
+/// Moves up to `len` bytes out of the pipe `from` into `to`. If `offset` is
+/// given, writing starts there and it is advanced instead of the file
+/// position of `to`.
+#[cfg(target_os = "linux")]
+pub fn splice_from_pipe(from: &AnonPipe, to: &FileDesc,
+                        offset: Option<&mut u64>, len: usize) -> io::Result<usize> {
+    splice(&from.0, None, to, offset, len)
+}
+
+/// Duplicates up to `len` bytes from the pipe `from` into the pipe `to`
+/// without consuming them.
+#[cfg(target_os = "linux")]
+pub fn tee(from: &AnonPipe, to: &AnonPipe, len: usize) -> io::Result<usize> {
+    let n = cvt_r(|| unsafe {
+        libc::tee(from.0.raw(), to.0.raw(), len, 0)
+    })?;
+    Ok(n as usize)
+}
+
+/// Copies the given buffers into the pipe, or as many of them as `IOV_MAX`
+/// allows. As `SPLICE_F_GIFT` isn't used,
+/// the buffers may be reused as soon as this returns.
+#[cfg(target_os = "linux")]
+pub fn vmsplice(pipe: &AnonPipe, bufs: &[&[u8]]) -> io::Result<usize> {
+    let iov = fd::iovecs(bufs);
+    let n = cvt_r(|| unsafe {
+        libc::vmsplice(pipe.0.raw(), iov.as_ptr(), iov.len(), 0)
+    })?;
+    Ok(n as usize)
+}
+
+/// Copies everything from `reader` to `writer`, returning the number of bytes
+/// copied.
+///
+/// The data is moved through an intermediate pipe with `splice`, so it never
+/// has to leave the kernel. If either descriptor doesn't support `splice`,
+/// this falls back to copying through a buffer.
+#[cfg(target_os = "linux")]
+pub fn copy(reader: &FileDesc, writer: &FileDesc) -> io::Result<u64> {
+    let (pipe_reader, pipe_writer) = anon_pipe()?;
+    let mut total = 0;
+
     loop {
-        // wait for either pipe to become readable using `poll`
-        cvt_r(|| unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) })?;
-
-        // Read as much as we can from each pipe, ignoring EWOULDBLOCK or
-        // EAGAIN. If we hit EOF, then this will happen because the underlying
-        // reader will return Ok(0), in which case we'll see `Ok` ourselves. In
-        // this case we flip the other fd back into blocking mode and read
-        // whatever's leftover on that file descriptor.
-        let read = |fd: &FileDesc, dst: &mut Vec<u8>| {
-            match fd.read_to_end(dst) {
-                Ok(_) => Ok(true),
-                Err(e) => {
-                    if e.raw_os_error() == Some(libc::EWOULDBLOCK) ||
-                       e.raw_os_error() == Some(libc::EAGAIN) {
-                        Ok(false)
-                    } else {
-                        Err(e)
+        let n = match splice(reader, None, &pipe_writer.0, None, PIPE_BUF_SIZE) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                return buffered_copy(reader, writer).map(|n| total + n);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut left = n;
+        while left > 0 {
+            match splice(&pipe_reader.0, None, writer, None, left) {
+                Ok(0) => return Err(write_zero()),
+                Ok(m) => left -= m,
+                Err(ref e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                    // Whatever already made it into the pipe still has to be
+                    // written out before copying the rest.
+                    let mut buf = vec![0; left];
+                    let mut filled = 0;
+                    while filled < left {
+                        filled += pipe_reader.read(&mut buf[filled..])?;
                     }
+                    write_all(writer, &buf)?;
+                    total += n as u64;
+                    return buffered_copy(reader, writer).map(|n| total + n);
                 }
+                Err(e) => return Err(e),
             }
-        };
-        if fds[0].revents != 0 && read(&p1, v1)? {
-            p2.set_nonblocking(false)?;
-            return p2.read_to_end(v2).map(|_| ());
         }
-        if fds[1].revents != 0 && read(&p2, v2)? {
-            p1.set_nonblocking(false)?;
-            return p1.read_to_end(v1).map(|_| ());
+        total += n as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn buffered_copy(reader: &FileDesc, writer: &FileDesc) -> io::Result<u64> {
+    let mut buf = vec![0; PIPE_BUF_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write_all(writer, &buf[..n])?;
+        total += n as u64;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn write_all(fd: &FileDesc, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        match fd.write(buf) {
+            Ok(0) => return Err(write_zero()),
+            Ok(n) => buf = &buf[n..],
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn write_zero() -> io::Error {
+    io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer")
 }
@@ -0,0 +1,334 @@
+//! Readiness notification for many file descriptors at once.
+//!
+//! Linux uses `epoll`, which supports both level- and edge-triggered
+//! notification. Elsewhere `poll` is used, which only knows about the former.
+
+use alloc::arc::Arc;
+use core::mem;
+use core::time::Duration;
+use fd::FileDesc;
+use io;
+use libc::{self, c_int};
+use pipe;
+
+pub use self::imp::Poller;
+
+/// The kinds of readiness a descriptor is registered for.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Interest {
+    pub const READABLE: Interest = Interest { readable: true, writable: false };
+    pub const WRITABLE: Interest = Interest { readable: false, writable: true };
+    pub const BOTH: Interest = Interest { readable: true, writable: true };
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+    /// Report a descriptor for as long as it is ready.
+    Level,
+    /// Report a descriptor only when it becomes ready, so it has to be read
+    /// or written until it would block before it is reported again. This is
+    /// only supported with `epoll`.
+    Edge,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Event {
+    /// The token the descriptor was registered with.
+    pub token: usize,
+    pub readable: bool,
+    pub writable: bool,
+    /// The other end was closed.
+    pub hangup: bool,
+    pub error: bool,
+}
+
+/// Wakes up a `Poller` blocked in `wait` from another thread.
+#[derive(Clone, Debug)]
+pub struct Waker {
+    fd: Arc<FileDesc>,
+}
+
+impl Waker {
+    pub fn wake(&self) -> io::Result<()> {
+        // An eventfd wants a full `u64` written to it, a pipe takes anything.
+        // Either way EAGAIN means there is a wakeup pending already.
+        let buf: [u8; 8] = unsafe { mem::transmute(1u64) };
+        match self.fd.write(&buf) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Reads a wakeup notification until there is nothing left.
+fn drain(fd: &FileDesc) -> io::Result<()> {
+    let mut buf = [0u8; 64];
+    loop {
+        match fd.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn timeout_ms(timeout: Option<Duration>) -> c_int {
+    timeout.map(pipe::timeout_ms).unwrap_or(-1)
+}
+
+// The token the waker is registered with internally; events for it are never
+// handed out, so callers can't use it themselves.
+const WAKER_TOKEN: usize = !0;
+
+fn check_token(token: usize) -> io::Result<()> {
+    if token == WAKER_TOKEN {
+        return Err(io::Error::from_raw_os_error(libc::EINVAL));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use ap::prelude::*;
+
+    use alloc::arc::Arc;
+    use core::time::Duration;
+    use fd::FileDesc;
+    use io;
+    use libc::{self, c_int};
+    use cvt;
+
+    use super::{Event, Interest, Mode, Waker, WAKER_TOKEN, check_token, drain, timeout_ms};
+
+    pub struct Poller {
+        epfd: FileDesc,
+        waker: Arc<FileDesc>,
+        events: Vec<libc::epoll_event>,
+    }
+
+    impl Poller {
+        pub fn new() -> io::Result<Poller> {
+            let epfd = FileDesc::new(cvt(unsafe {
+                libc::epoll_create1(libc::EPOLL_CLOEXEC)
+            })?);
+            let waker = FileDesc::new(cvt(unsafe {
+                libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK)
+            })?);
+            let poller = Poller {
+                epfd: epfd,
+                waker: Arc::new(waker),
+                events: Vec::with_capacity(256),
+            };
+            poller.ctl(libc::EPOLL_CTL_ADD, &poller.waker, WAKER_TOKEN, libc::EPOLLIN as u32)?;
+            Ok(poller)
+        }
+
+        pub fn register(&mut self, fd: &FileDesc, token: usize,
+                        interest: Interest, mode: Mode) -> io::Result<()> {
+            check_token(token)?;
+            self.ctl(libc::EPOLL_CTL_ADD, fd, token, flags(interest, mode))
+        }
+
+        pub fn reregister(&mut self, fd: &FileDesc, token: usize,
+                          interest: Interest, mode: Mode) -> io::Result<()> {
+            check_token(token)?;
+            self.ctl(libc::EPOLL_CTL_MOD, fd, token, flags(interest, mode))
+        }
+
+        pub fn deregister(&mut self, fd: &FileDesc) -> io::Result<()> {
+            // Kernels before 2.6.9 insist on an event even though it's
+            // ignored, so pass one anyway.
+            self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+        }
+
+        /// Waits for at least one registered descriptor to become ready, the
+        /// waker to be woken or `timeout` to pass, and appends what happened
+        /// to `events`. Being interrupted by a signal counts as a timeout.
+        pub fn wait(&mut self, events: &mut Vec<Event>,
+                    timeout: Option<Duration>) -> io::Result<()> {
+            let n = match cvt(unsafe {
+                libc::epoll_wait(self.epfd.raw(),
+                                 self.events.as_mut_ptr(),
+                                 self.events.capacity() as c_int,
+                                 timeout_ms(timeout))
+            }) {
+                Ok(n) => n as usize,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => 0,
+                Err(e) => return Err(e),
+            };
+            unsafe { self.events.set_len(n); }
+
+            for ev in &self.events {
+                let token = ev.u64 as usize;
+                let flags = ev.events as c_int;
+                if token == WAKER_TOKEN {
+                    drain(&self.waker)?;
+                    continue;
+                }
+                events.push(Event {
+                    token: token,
+                    readable: flags & (libc::EPOLLIN | libc::EPOLLPRI | libc::EPOLLRDHUP) != 0,
+                    writable: flags & libc::EPOLLOUT != 0,
+                    hangup: flags & (libc::EPOLLHUP | libc::EPOLLRDHUP) != 0,
+                    error: flags & libc::EPOLLERR != 0,
+                });
+            }
+            Ok(())
+        }
+
+        pub fn waker(&self) -> Waker {
+            Waker { fd: self.waker.clone() }
+        }
+
+        fn ctl(&self, op: c_int, fd: &FileDesc, token: usize, flags: u32) -> io::Result<()> {
+            let mut event = libc::epoll_event { events: flags, u64: token as u64 };
+            cvt(unsafe { libc::epoll_ctl(self.epfd.raw(), op, fd.raw(), &mut event) })?;
+            Ok(())
+        }
+    }
+
+    fn flags(interest: Interest, mode: Mode) -> u32 {
+        let mut flags = libc::EPOLLRDHUP;
+        if interest.readable {
+            flags |= libc::EPOLLIN;
+        }
+        if interest.writable {
+            flags |= libc::EPOLLOUT;
+        }
+        if mode == Mode::Edge {
+            flags |= libc::EPOLLET;
+        }
+        flags as u32
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use ap::prelude::*;
+
+    use alloc::arc::Arc;
+    use core::time::Duration;
+    use fd::FileDesc;
+    use io;
+    use libc;
+    use pipe::PipeBuilder;
+    use cvt;
+
+    use super::{Event, Interest, Mode, Waker, WAKER_TOKEN, check_token, drain, timeout_ms};
+
+    pub struct Poller {
+        // The waker's reading end always comes first.
+        fds: Vec<libc::pollfd>,
+        tokens: Vec<usize>,
+        waker_reader: FileDesc,
+        waker: Arc<FileDesc>,
+    }
+
+    impl Poller {
+        pub fn new() -> io::Result<Poller> {
+            let mut builder = PipeBuilder::new();
+            builder.nonblocking(true);
+            let (reader, writer) = builder.create()?;
+            let reader = reader.into_fd();
+            Ok(Poller {
+                fds: vec![libc::pollfd { fd: reader.raw(), events: libc::POLLIN, revents: 0 }],
+                tokens: vec![WAKER_TOKEN],
+                waker_reader: reader,
+                waker: Arc::new(writer.into_fd()),
+            })
+        }
+
+        pub fn register(&mut self, fd: &FileDesc, token: usize,
+                        interest: Interest, mode: Mode) -> io::Result<()> {
+            check_token(token)?;
+            if mode == Mode::Edge {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            if self.position(fd).is_some() {
+                return Err(io::Error::from_raw_os_error(libc::EEXIST));
+            }
+            self.fds.push(libc::pollfd { fd: fd.raw(), events: events(interest), revents: 0 });
+            self.tokens.push(token);
+            Ok(())
+        }
+
+        pub fn reregister(&mut self, fd: &FileDesc, token: usize,
+                          interest: Interest, mode: Mode) -> io::Result<()> {
+            check_token(token)?;
+            if mode == Mode::Edge {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            let i = self.position(fd).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+            self.fds[i].events = events(interest);
+            self.tokens[i] = token;
+            Ok(())
+        }
+
+        pub fn deregister(&mut self, fd: &FileDesc) -> io::Result<()> {
+            let i = self.position(fd).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+            self.fds.swap_remove(i);
+            self.tokens.swap_remove(i);
+            Ok(())
+        }
+
+        /// Waits for at least one registered descriptor to become ready, the
+        /// waker to be woken or `timeout` to pass, and appends what happened
+        /// to `events`. Being interrupted by a signal counts as a timeout.
+        pub fn wait(&mut self, events: &mut Vec<Event>,
+                    timeout: Option<Duration>) -> io::Result<()> {
+            match cvt(unsafe {
+                libc::poll(self.fds.as_mut_ptr(), self.fds.len() as libc::nfds_t,
+                           timeout_ms(timeout))
+            }) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+                Err(e) => return Err(e),
+            }
+
+            for (fd, &token) in self.fds.iter().zip(self.tokens.iter()) {
+                if fd.revents == 0 {
+                    continue;
+                }
+                if token == WAKER_TOKEN {
+                    drain(&self.waker_reader)?;
+                    continue;
+                }
+                events.push(Event {
+                    token: token,
+                    readable: fd.revents & (libc::POLLIN | libc::POLLPRI) != 0,
+                    writable: fd.revents & libc::POLLOUT != 0,
+                    hangup: fd.revents & libc::POLLHUP != 0,
+                    error: fd.revents & (libc::POLLERR | libc::POLLNVAL) != 0,
+                });
+            }
+            Ok(())
+        }
+
+        pub fn waker(&self) -> Waker {
+            Waker { fd: self.waker.clone() }
+        }
+
+        fn position(&self, fd: &FileDesc) -> Option<usize> {
+            self.fds.iter().skip(1).position(|p| p.fd == fd.raw()).map(|i| i + 1)
+        }
+    }
+
+    fn events(interest: Interest) -> i16 {
+        let mut events = 0;
+        if interest.readable {
+            events |= libc::POLLIN;
+        }
+        if interest.writable {
+            events |= libc::POLLOUT;
+        }
+        events
+    }
+}
@@ -21,16 +21,19 @@ pub fn hashmap_random_keys() -> (u64, u64) {
     return v
 }
 
+pub use self::imp::fill_bytes;
+
 #[cfg(all(unix,
           not(target_os = "ios"),
           not(target_os = "openbsd"),
           not(target_os = "freebsd"),
           not(target_os = "fuchsia")))]
 mod imp {
-    use fs::File;
     use io::Read;
     use libc;
+    use fd::FileDesc;
     use ::os::errno;
+    use cvt_r;
 
     #[cfg(any(target_os = "linux", target_os = "android"))]
     fn getrandom(buf: &mut [u8]) -> libc::c_long {
@@ -66,13 +69,14 @@ mod imp {
     #[cfg(any(target_os = "linux", target_os = "android"))]
     fn is_getrandom_available() -> bool {
         use io;
-        use core::sync::atomic::{AtomicBool, Ordering};
-        use sync::Once;
+        use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 
-        static CHECKER: Once = Once::new();
-        static AVAILABLE: AtomicBool = AtomicBool::new(false);
+        static CHECKED: AtomicBool = ATOMIC_BOOL_INIT;
+        static AVAILABLE: AtomicBool = ATOMIC_BOOL_INIT;
 
-        CHECKER.call_once(|| {
+        // Racing threads may both do the check, but they'll come to the same
+        // conclusion, so there's no need for anything stronger than this.
+        if !CHECKED.load(Ordering::Relaxed) {
             let mut buf: [u8; 0] = [];
             let result = getrandom(&mut buf);
             let available = if result == -1 {
@@ -82,7 +86,8 @@ mod imp {
                 true
             };
             AVAILABLE.store(available, Ordering::Relaxed);
-        });
+            CHECKED.store(true, Ordering::Relaxed);
+        }
 
         AVAILABLE.load(Ordering::Relaxed)
     }
@@ -102,16 +107,20 @@ mod imp {
             return
         }
 
-        let mut file = File::open("/dev/urandom")
-            .expect("failed to open /dev/urandom");
-        file.read_exact(v).expect("failed to read /dev/urandom");
+        let fd = cvt_r(|| unsafe {
+            libc::open(b"/dev/urandom\0".as_ptr() as *const libc::c_char,
+                       libc::O_RDONLY | libc::O_CLOEXEC)
+        }).expect("failed to open /dev/urandom");
+        let fd = FileDesc::new(fd);
+        let mut reader = &fd;
+        reader.read_exact(v).expect("failed to read /dev/urandom");
     }
 }
 
 #[cfg(target_os = "openbsd")]
 mod imp {
     use libc;
-    use sys::os::errno;
+    use ::os::errno;
 
     pub fn fill_bytes(v: &mut [u8]) {
         // getentropy(2) permits a maximum buffer size of 256 bytes
@@ -130,7 +139,7 @@ mod imp {
 mod imp {
     use io;
     use libc::{c_int, size_t};
-    use ptr;
+    use core::ptr;
 
     enum SecRandom {}
 
@@ -159,7 +168,7 @@ mod imp {
 #[cfg(target_os = "freebsd")]
 mod imp {
     use libc;
-    use ptr;
+    use core::ptr;
 
     pub fn fill_bytes(v: &mut [u8]) {
         let mib = [libc::CTL_KERN, libc::KERN_ARND];
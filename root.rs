@@ -0,0 +1,282 @@
+//! A view of the filesystem confined to a single directory.
+//!
+//! Every path handed to a `Root` is resolved beneath its directory: absolute
+//! paths, `..` components and symlinks that would lead outside of it are
+//! rejected with `PermissionDenied`. On Linux 5.6 and later the kernel does
+//! this for us through `openat2`, elsewhere the path is walked one component
+//! at a time.
+
+use ap::prelude::*;
+use ap::sys_common::FromInner;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use traits::File as FileT;
+
+use ffi::{CString, CStr};
+use fd::FileDesc;
+use fs::{self, File, FileAttr, OpenOptions, ReadDir};
+use io::{self, Error, ErrorKind};
+use libc::{self, c_int};
+use path::{Path, PathBuf};
+#[cfg(target_os = "linux")]
+use core::mem;
+#[cfg(target_os = "linux")]
+use core::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use {cvt, cvt_r};
+
+// The flags used to open directories that are only walked through. `O_PATH`
+// doesn't need read permission, so prefer it where we have it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const O_SEARCH: c_int = libc::O_PATH;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const O_SEARCH: c_int = libc::O_RDONLY;
+
+#[derive(Debug)]
+pub struct Root {
+    dir: FileDesc,
+    path: PathBuf,
+}
+
+impl Root {
+    /// Opens the directory `p` to serve as the root.
+    pub fn open(p: &Path) -> io::Result<Root> {
+        let c_path = CString::new(p.as_os_str().as_bytes())?;
+        let fd = cvt_r(|| unsafe {
+            libc::open(c_path.as_ptr(), O_SEARCH | libc::O_DIRECTORY | libc::O_CLOEXEC)
+        })?;
+        Ok(Root { dir: FileDesc::new(fd), path: p.to_path_buf() })
+    }
+
+    pub fn open_file(&self, p: &Path, opts: &OpenOptions) -> io::Result<File> {
+        let fd = self.open_beneath(p, opts.open_flags()?, opts.get_mode())?;
+        Ok(File::from_inner(fd.into_raw()))
+    }
+
+    /// Returns the attributes of the file at `p`, following symlinks.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn metadata(&self, p: &Path) -> io::Result<FileAttr> {
+        let fd = self.open_beneath(p, O_SEARCH | libc::O_CLOEXEC, 0)?;
+        File::from_inner(fd.into_raw()).file_attr()
+    }
+
+    /// Returns the attributes of the file at `p`, following symlinks.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn metadata(&self, p: &Path) -> io::Result<FileAttr> {
+        // Without `O_PATH` opening the file would take read permission, which
+        // `stat` doesn't, so stat it from its directory instead. The walk has
+        // resolved any symlinks by then; not following one that shows up in
+        // the meantime keeps us from being led outside of the root.
+        if p.is_absolute() {
+            return Err(escape_error());
+        }
+        self.walk(p, |dir, name| fs::lstat_at(dir, name))
+    }
+
+    /// Returns the attributes of the file at `p` without following a symlink
+    /// in the last component.
+    pub fn symlink_metadata(&self, p: &Path) -> io::Result<FileAttr> {
+        let (dir, name) = self.open_parent(p)?;
+        fs::lstat_at(&dir, &name)
+    }
+
+    /// Lists the directory at `p`. The paths of the entries are joined onto
+    /// the path the root was opened with.
+    pub fn read_dir(&self, p: &Path) -> io::Result<ReadDir> {
+        let fd = self.open_beneath(p,
+                                   libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+                                   0)?;
+        fs::readdir_fd(fd, self.path.join(p))
+    }
+
+    pub fn rename(&self, old: &Path, new: &Path) -> io::Result<()> {
+        let (old_dir, old_name) = self.open_parent(old)?;
+        let (new_dir, new_name) = self.open_parent(new)?;
+        cvt(unsafe {
+            libc::renameat(old_dir.raw(), old_name.as_ptr(),
+                           new_dir.raw(), new_name.as_ptr())
+        })?;
+        Ok(())
+    }
+
+    pub fn unlink(&self, p: &Path) -> io::Result<()> {
+        let (dir, name) = self.open_parent(p)?;
+        cvt(unsafe { libc::unlinkat(dir.raw(), name.as_ptr(), 0) })?;
+        Ok(())
+    }
+
+    pub fn rmdir(&self, p: &Path) -> io::Result<()> {
+        let (dir, name) = self.open_parent(p)?;
+        cvt(unsafe { libc::unlinkat(dir.raw(), name.as_ptr(), libc::AT_REMOVEDIR) })?;
+        Ok(())
+    }
+
+    pub fn mkdir(&self, p: &Path, mode: u32) -> io::Result<()> {
+        let (dir, name) = self.open_parent(p)?;
+        cvt(unsafe { libc::mkdirat(dir.raw(), name.as_ptr(), mode as libc::mode_t) })?;
+        Ok(())
+    }
+
+    // Splits off the last component of `p`, which the `*at` calls operate on
+    // without following it, and opens the directory containing it.
+    fn open_parent(&self, p: &Path) -> io::Result<(FileDesc, CString)> {
+        let bytes = p.as_os_str().as_bytes();
+        let (parent, name) = match bytes.iter().rposition(|&b| b == b'/') {
+            Some(i) => (&bytes[..i + 1], &bytes[i + 1..]),
+            None => (&b""[..], bytes),
+        };
+        if name.is_empty() || name == b"." || name == b".." {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        let parent = PathBuf::from(::ffi::OsStr::from_bytes(parent));
+        let dir = self.open_beneath(&parent,
+                                    O_SEARCH | libc::O_DIRECTORY | libc::O_CLOEXEC,
+                                    0)?;
+        Ok((dir, CString::new(name)?))
+    }
+
+    fn open_beneath(&self, p: &Path, flags: c_int, mode: u32) -> io::Result<FileDesc> {
+        if p.is_absolute() {
+            return Err(escape_error());
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ret) = self.openat2(p, flags, mode) {
+                return ret;
+            }
+        }
+
+        self.walk(p, |dir, name| open_at(dir, name, flags, mode))
+    }
+
+    // Returns `None` if the kernel doesn't support `openat2`.
+    #[cfg(target_os = "linux")]
+    fn openat2(&self, p: &Path, flags: c_int, mode: u32) -> Option<io::Result<FileDesc>> {
+        static UNAVAILABLE: AtomicBool = ATOMIC_BOOL_INIT;
+
+        if UNAVAILABLE.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        // `openat2` refuses an empty path with ENOENT, so name the root
+        // itself explicitly, e.g. for the parent of "foo".
+        let mut bytes = p.as_os_str().as_bytes();
+        if bytes.split(|&b| b == b'/').all(|c| c.is_empty() || c == b".") {
+            bytes = b".";
+        }
+        let c_path = match CString::new(bytes) {
+            Ok(c_path) => c_path,
+            Err(e) => return Some(Err(e.into())),
+        };
+        let mut how: libc::open_how = unsafe { mem::zeroed() };
+        how.flags = flags as u64;
+        // The kernel rejects a mode unless a file may be created.
+        if flags & (libc::O_CREAT | libc::O_TMPFILE) != 0 {
+            how.mode = mode as u64;
+        }
+        how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+
+        loop {
+            let ret = cvt(unsafe {
+                libc::syscall(libc::SYS_openat2, self.dir.raw(), c_path.as_ptr(),
+                              &how as *const libc::open_how, mem::size_of::<libc::open_how>())
+                    as c_int
+            });
+            return match ret {
+                Ok(fd) => Some(Ok(FileDesc::new(fd))),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                // A concurrent rename somewhere on the system made the kernel
+                // unsure whether the lookup stayed beneath the root.
+                Err(ref e) if e.raw_os_error() == Some(libc::EAGAIN) => continue,
+                Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                    Some(Err(escape_error()))
+                }
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+                    UNAVAILABLE.store(true, Ordering::Relaxed);
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            };
+        }
+    }
+
+    // Resolves `p` one component at a time, never following a symlink
+    // without looking at it first, and hands the directory holding the result
+    // and its name to `last`.
+    fn walk<T, F>(&self, p: &Path, last: F) -> io::Result<T>
+        where F: Fn(&FileDesc, &CStr) -> io::Result<T>
+    {
+        let mut pending = Vec::new();
+        fs::push_components(&mut pending, p.as_os_str().as_bytes());
+
+        // The directories we descended into. Going up with `..` pops them
+        // again, which is how we notice an attempt to leave the root.
+        let mut dirs: Vec<FileDesc> = Vec::new();
+        let mut symlinks = 0;
+
+        while let Some(component) = pending.pop() {
+            if component == b"." {
+                continue;
+            }
+            if component == b".." {
+                if dirs.pop().is_none() {
+                    return Err(escape_error());
+                }
+                continue;
+            }
+
+            let name = CString::new(component)?;
+            let is_last = pending.is_empty();
+            let attr = {
+                let dir = dirs.last().unwrap_or(&self.dir);
+                match fs::lstat_at(dir, &name) {
+                    Ok(attr) => attr,
+                    // Let the final `openat` decide whether the last
+                    // component may be created.
+                    Err(ref e) if is_last && e.kind() == ErrorKind::NotFound => {
+                        return last(dir, &name);
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+
+            if attr.file_type().is_symlink() {
+                symlinks += 1;
+                if symlinks > fs::MAX_SYMLINKS {
+                    return Err(Error::from_raw_os_error(libc::ELOOP));
+                }
+                let target = fs::readlink_at(dirs.last().unwrap_or(&self.dir).raw(), &name)?;
+                if target.first() == Some(&b'/') {
+                    return Err(escape_error());
+                }
+                fs::push_components(&mut pending, &target);
+                continue;
+            }
+
+            let next = {
+                let dir = dirs.last().unwrap_or(&self.dir);
+                if is_last {
+                    return last(dir, &name);
+                }
+                open_at(dir, &name, O_SEARCH | libc::O_DIRECTORY | libc::O_CLOEXEC, 0)?
+            };
+            dirs.push(next);
+        }
+
+        // The path named a directory we already hold, e.g. "" or "a/..".
+        let dot = CString::new(".")?;
+        last(dirs.last().unwrap_or(&self.dir), &dot)
+    }
+}
+
+fn escape_error() -> Error {
+    Error::new(ErrorKind::PermissionDenied, "path escapes the root directory")
+}
+
+// Opens `name` in `dir`, refusing to follow it if it's a symlink that
+// appeared since we looked at it.
+fn open_at(dir: &FileDesc, name: &CStr, flags: c_int, mode: u32) -> io::Result<FileDesc> {
+    let fd = cvt_r(|| unsafe {
+        libc::openat(dir.raw(), name.as_ptr(), flags | libc::O_NOFOLLOW, mode as c_int)
+    })?;
+    Ok(FileDesc::new(fd))
+}
@@ -13,7 +13,7 @@ use ap::traits::Stdio;
 use ap::io;
 
 use libc;
-use fd::FileDesc;
+use fd::BorrowedFd;
 
 pub struct Stdin(());
 pub struct Stdout(());
@@ -25,10 +25,7 @@ impl Stdio<Std> for Stdin {
 
 impl io::Read<Std> for Stdin {
     fn read(&mut self, data: &mut [u8]) -> io::Result<usize, Std> {
-        let fd = FileDesc::new(libc::STDIN_FILENO);
-        let ret = fd.read(data);
-        fd.into_raw();
-        ret
+        stdin_fd().read(data)
     }
 }
 
@@ -38,10 +35,7 @@ impl Stdio<Std> for Stdout {
 
 impl io::Write<Std> for Stdout {
     fn write(&mut self, data: &[u8]) -> io::Result<usize, Std> {
-        let fd = FileDesc::new(libc::STDOUT_FILENO);
-        let ret = fd.write(data);
-        fd.into_raw();
-        ret
+        stdout_fd().write(data)
     }
 
     fn flush(&mut self) -> io::Result<(), Std> {
@@ -55,10 +49,7 @@ impl Stdio<Std> for Stderr {
 
 impl io::Write<Std> for Stderr {
     fn write(&mut self, data: &[u8]) -> io::Result<usize, Std> {
-        let fd = FileDesc::new(libc::STDERR_FILENO);
-        let ret = fd.write(data);
-        fd.into_raw();
-        ret
+        stderr_fd().write(data)
     }
 
     fn flush(&mut self) -> io::Result<(), Std> {
@@ -66,6 +57,20 @@ impl io::Write<Std> for Stderr {
     }
 }
 
+// The standard descriptors are never closed by us, so they can be borrowed
+// for as long as we like.
+fn stdin_fd() -> BorrowedFd<'static> {
+    unsafe { BorrowedFd::borrow_raw(libc::STDIN_FILENO) }
+}
+
+fn stdout_fd() -> BorrowedFd<'static> {
+    unsafe { BorrowedFd::borrow_raw(libc::STDOUT_FILENO) }
+}
+
+fn stderr_fd() -> BorrowedFd<'static> {
+    unsafe { BorrowedFd::borrow_raw(libc::STDERR_FILENO) }
+}
+
 pub fn is_ebadf(err: &io::Error<Std>) -> bool {
     err.raw_os_error() == Some(libc::EBADF as i32)
 }
@@ -222,6 +222,12 @@ mod inner {
         }
     }
 
+    impl From<SystemTime> for libc::timespec {
+        fn from(t: SystemTime) -> libc::timespec {
+            t.t.t
+        }
+    }
+
     impl fmt::Debug for SystemTime {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
             f.debug_struct("SystemTime")
@@ -338,6 +344,12 @@ mod inner {
         }
     }
 
+    impl From<SystemTime> for libc::timespec {
+        fn from(t: SystemTime) -> libc::timespec {
+            t.t.t
+        }
+    }
+
     impl fmt::Debug for SystemTime {
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
             f.debug_struct("SystemTime")
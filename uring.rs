@@ -0,0 +1,525 @@
+//! Batched asynchronous file IO through io_uring (Linux 5.6 and later).
+//!
+//! Requests are described by `Entry`s, queued with `Ring::push` and handed to
+//! the kernel with `Ring::submit`; their results come back as `Completion`s in
+//! whatever order they finish, tagged with the entry's user data. Whether the
+//! kernel supports io_uring at all is only known at runtime, so `Ring::new`
+//! fails with ENOSYS (or EPERM if a seccomp filter or `io_uring_disabled`
+//! forbids it) and callers are expected to fall back to `read_at`/`write_at`.
+
+#![allow(non_camel_case_types)]
+
+use ap::prelude::*;
+
+use core::cmp;
+use core::mem;
+use core::ptr;
+use core::sync::atomic::{self, AtomicBool, ATOMIC_BOOL_INIT, Ordering};
+use ffi::CStr;
+use fd::FileDesc;
+use fs::{FileAttr, OpenOptions};
+use io;
+use libc::{self, c_int, c_uint, c_void};
+use cvt;
+
+// The kernel interface from <linux/io_uring.h>, which libc doesn't cover
+// beyond the syscall numbers.
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+
+const IORING_ENTER_GETEVENTS: c_uint = 1 << 0;
+
+const IORING_REGISTER_BUFFERS: c_uint = 0;
+const IORING_UNREGISTER_BUFFERS: c_uint = 1;
+
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ_FIXED: u8 = 4;
+const IORING_OP_WRITE_FIXED: u8 = 5;
+const IORING_OP_OPENAT: u8 = 18;
+const IORING_OP_CLOSE: u8 = 19;
+const IORING_OP_STATX: u8 = 21;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+
+const IORING_FSYNC_DATASYNC: u32 = 1 << 0;
+
+const IOSQE_IO_LINK: u8 = 1 << 2;
+const IOSQE_IO_HARDLINK: u8 = 1 << 3;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)] // mirrors the kernel's layout
+struct io_sqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)] // mirrors the kernel's layout
+struct io_cqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)] // mirrors the kernel's layout
+struct io_uring_params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)] // mirrors the kernel's layout
+struct io_uring_sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    // Also `addr2`, e.g. the `statx` buffer.
+    off: u64,
+    addr: u64,
+    len: u32,
+    // `rw_flags`, `fsync_flags`, `open_flags`, `statx_flags`, ...
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    pad2: [u64; 2],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+#[allow(dead_code)] // mirrors the kernel's layout
+struct io_uring_cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+// Set once `io_uring_setup` has told us it isn't there, so we don't keep
+// asking.
+static UNSUPPORTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Returns `false` if an earlier `Ring::new` found io_uring unavailable.
+/// This doesn't probe the kernel itself, so `true` only means it's worth
+/// trying.
+pub fn is_supported() -> bool {
+    !UNSUPPORTED.load(Ordering::Relaxed)
+}
+
+/// A single request, ready to be pushed onto a `Ring`.
+#[derive(Copy, Clone)]
+pub struct Entry(io_uring_sqe);
+
+impl Entry {
+    fn new(opcode: u8, fd: c_int) -> Entry {
+        let mut sqe: io_uring_sqe = unsafe { mem::zeroed() };
+        sqe.opcode = opcode;
+        sqe.fd = fd;
+        Entry(sqe)
+    }
+
+    /// Reads into `buf` from `offset`, like `read_at`.
+    pub fn read_at(fd: &FileDesc, buf: &mut [u8], offset: u64) -> Entry {
+        let mut entry = Entry::new(IORING_OP_READ, fd.raw());
+        entry.0.addr = buf.as_mut_ptr() as u64;
+        entry.0.len = max_len(buf.len());
+        entry.0.off = offset;
+        entry
+    }
+
+    /// Writes `buf` at `offset`, like `write_at`.
+    pub fn write_at(fd: &FileDesc, buf: &[u8], offset: u64) -> Entry {
+        let mut entry = Entry::new(IORING_OP_WRITE, fd.raw());
+        entry.0.addr = buf.as_ptr() as u64;
+        entry.0.len = max_len(buf.len());
+        entry.0.off = offset;
+        entry
+    }
+
+    /// Like `read_at`, but `buf` has to lie within the buffer registered at
+    /// `index`, which saves the kernel from mapping it for every request.
+    pub fn read_fixed(fd: &FileDesc, buf: &mut [u8], offset: u64, index: u16) -> Entry {
+        let mut entry = Entry::read_at(fd, buf, offset);
+        entry.0.opcode = IORING_OP_READ_FIXED;
+        entry.0.buf_index = index;
+        entry
+    }
+
+    /// Like `write_at`, but with a registered buffer; see `read_fixed`.
+    pub fn write_fixed(fd: &FileDesc, buf: &[u8], offset: u64, index: u16) -> Entry {
+        let mut entry = Entry::write_at(fd, buf, offset);
+        entry.0.opcode = IORING_OP_WRITE_FIXED;
+        entry.0.buf_index = index;
+        entry
+    }
+
+    /// Flushes the file to disk, like `fsync`, or `fdatasync` if `datasync`
+    /// is set.
+    pub fn fsync(fd: &FileDesc, datasync: bool) -> Entry {
+        let mut entry = Entry::new(IORING_OP_FSYNC, fd.raw());
+        if datasync {
+            entry.0.op_flags = IORING_FSYNC_DATASYNC;
+        }
+        entry
+    }
+
+    /// Opens `path` relative to `dir`, or to the working directory if `dir`
+    /// is `None`. The completion's result is the new descriptor.
+    pub fn openat(dir: Option<&FileDesc>, path: &CStr, opts: &OpenOptions) -> io::Result<Entry> {
+        let mut entry = Entry::new(IORING_OP_OPENAT, dirfd(dir));
+        entry.0.addr = path.as_ptr() as u64;
+        entry.0.len = opts.get_mode();
+        entry.0.op_flags = opts.open_flags()? as u32;
+        Ok(entry)
+    }
+
+    /// Fetches the attributes of `path` relative to `dir` (or the working
+    /// directory) into `buf`, like `statx`. `flags` are the `AT_*` flags,
+    /// e.g. `AT_SYMLINK_NOFOLLOW`, or `AT_EMPTY_PATH` to stat `dir` itself.
+    pub fn statx(dir: Option<&FileDesc>, path: &CStr, flags: c_int,
+                 buf: &mut StatxBuf) -> Entry {
+        let mut entry = Entry::new(IORING_OP_STATX, dirfd(dir));
+        entry.0.addr = path.as_ptr() as u64;
+        entry.0.len = libc::STATX_ALL;
+        entry.0.op_flags = flags as u32;
+        entry.0.off = &mut buf.0 as *mut libc::statx as u64;
+        entry
+    }
+
+    /// Closes `fd`. It is taken over by the entry, so if the entry is never
+    /// submitted the descriptor leaks.
+    pub fn close(fd: FileDesc) -> Entry {
+        Entry::new(IORING_OP_CLOSE, fd.into_raw())
+    }
+
+    /// Sets the value the completion is tagged with.
+    pub fn set_user_data(&mut self, user_data: u64) { self.0.user_data = user_data; }
+
+    /// Links the next entry pushed to this one, so that it only starts once
+    /// this one has completed. If this one fails (or is short), the rest of
+    /// the chain is cancelled with ECANCELED.
+    pub fn set_link(&mut self, link: bool) { self.set_flag(IOSQE_IO_LINK, link) }
+
+    /// Like `set_link`, but the chain carries on even if this entry fails.
+    pub fn set_hard_link(&mut self, link: bool) { self.set_flag(IOSQE_IO_HARDLINK, link) }
+
+    fn set_flag(&mut self, flag: u8, set: bool) {
+        if set {
+            self.0.flags |= flag;
+        } else {
+            self.0.flags &= !flag;
+        }
+    }
+}
+
+// The kernel takes a 32-bit length, so larger buffers are only partially
+// used, much like `read` caps them at `SSIZE_MAX`.
+fn max_len(len: usize) -> u32 {
+    cmp::min(len, u32::max_value() as usize) as u32
+}
+
+fn dirfd(dir: Option<&FileDesc>) -> c_int {
+    dir.map(|d| d.raw()).unwrap_or(libc::AT_FDCWD)
+}
+
+/// Where a `statx` request puts its result.
+pub struct StatxBuf(libc::statx);
+
+impl StatxBuf {
+    pub fn new() -> StatxBuf {
+        StatxBuf(unsafe { mem::zeroed() })
+    }
+
+    /// Only meaningful once the request completed successfully.
+    pub fn attr(&self) -> FileAttr {
+        FileAttr::from_statx(&self.0)
+    }
+}
+
+/// The outcome of a request.
+#[derive(Copy, Clone, Debug)]
+pub struct Completion {
+    user_data: u64,
+    res: i32,
+}
+
+impl Completion {
+    pub fn user_data(&self) -> u64 { self.user_data }
+
+    /// The number of bytes transferred, the new descriptor for `openat` or 0
+    /// for everything else.
+    pub fn result(&self) -> io::Result<u32> {
+        if self.res < 0 {
+            Err(io::Error::from_raw_os_error(-self.res))
+        } else {
+            Ok(self.res as u32)
+        }
+    }
+}
+
+// One of the regions shared with the kernel.
+struct RingMap {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl RingMap {
+    fn new(fd: &FileDesc, len: usize, offset: libc::off_t) -> io::Result<RingMap> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE, fd.raw(), offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(RingMap { ptr: ptr, len: len })
+    }
+
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        (self.ptr as *mut u8).offset(offset as isize) as *mut T
+    }
+}
+
+impl Drop for RingMap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len); }
+    }
+}
+
+pub struct Ring {
+    // Only kept around to be unmapped, which happens before the descriptor
+    // is closed.
+    _sq_map: RingMap,
+    _cq_map: Option<RingMap>,
+    _sqes_map: RingMap,
+    fd: FileDesc,
+
+    sq_head: *const u32,
+    sq_tail: *mut u32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut io_uring_sqe,
+    // Entries pushed but not yet made visible to the kernel.
+    sq_pending: u32,
+
+    cq_head: *mut u32,
+    cq_tail: *const u32,
+    cq_mask: u32,
+    cqes: *const io_uring_cqe,
+}
+
+unsafe impl Send for Ring {}
+
+impl Ring {
+    /// Sets up a ring with room for at least `entries` requests in flight.
+    pub fn new(entries: u32) -> io::Result<Ring> {
+        if UNSUPPORTED.load(Ordering::Relaxed) {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+
+        let mut params: io_uring_params = unsafe { mem::zeroed() };
+        let fd = match cvt(unsafe {
+            libc::syscall(libc::SYS_io_uring_setup, entries, &mut params) as c_int
+        }) {
+            Ok(fd) => FileDesc::new(fd),
+            Err(e) => {
+                if e.raw_os_error() == Some(libc::ENOSYS) {
+                    UNSUPPORTED.store(true, Ordering::Relaxed);
+                }
+                return Err(e);
+            }
+        };
+
+        let sq_off = params.sq_off;
+        let cq_off = params.cq_off;
+        let mut sq_len = sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>();
+        let cq_len = cq_off.cqes as usize +
+                     params.cq_entries as usize * mem::size_of::<io_uring_cqe>();
+
+        // Since 5.4 both rings live in a single mapping.
+        let single_mmap = params.features & IORING_FEAT_SINGLE_MMAP != 0;
+        if single_mmap && cq_len > sq_len {
+            sq_len = cq_len;
+        }
+        let sq_map = RingMap::new(&fd, sq_len, IORING_OFF_SQ_RING)?;
+        let cq_map = if single_mmap {
+            None
+        } else {
+            Some(RingMap::new(&fd, cq_len, IORING_OFF_CQ_RING)?)
+        };
+        let sqes_map = RingMap::new(&fd,
+                                    params.sq_entries as usize * mem::size_of::<io_uring_sqe>(),
+                                    IORING_OFF_SQES)?;
+
+        unsafe {
+            let (sq_head, sq_tail, sq_mask, sq_array, sqes) =
+                (sq_map.at::<u32>(sq_off.head), sq_map.at::<u32>(sq_off.tail),
+                 *sq_map.at::<u32>(sq_off.ring_mask), sq_map.at::<u32>(sq_off.array),
+                 sqes_map.at::<io_uring_sqe>(0));
+            let (cq_head, cq_tail, cq_mask, cqes) = {
+                let map = cq_map.as_ref().unwrap_or(&sq_map);
+                (map.at::<u32>(cq_off.head), map.at::<u32>(cq_off.tail),
+                 *map.at::<u32>(cq_off.ring_mask), map.at::<io_uring_cqe>(cq_off.cqes))
+            };
+            Ok(Ring {
+                _sq_map: sq_map,
+                _cq_map: cq_map,
+                _sqes_map: sqes_map,
+                fd: fd,
+                sq_head: sq_head,
+                sq_tail: sq_tail,
+                sq_mask: sq_mask,
+                sq_entries: params.sq_entries,
+                sq_array: sq_array,
+                sqes: sqes,
+                sq_pending: 0,
+                cq_head: cq_head,
+                cq_tail: cq_tail,
+                cq_mask: cq_mask,
+                cqes: cqes,
+            })
+        }
+    }
+
+    pub fn fd(&self) -> &FileDesc { &self.fd }
+
+    /// Queues `entry`, failing with WouldBlock if the submission queue is
+    /// full; `submit` to make room.
+    ///
+    /// This is unsafe because the kernel works on whatever the entry points
+    /// to (buffers, paths, `StatxBuf`s) until its completion has been
+    /// reaped, so all of that has to stay alive and untouched until then.
+    pub unsafe fn push(&mut self, entry: &Entry) -> io::Result<()> {
+        let head = load_acquire(self.sq_head);
+        let tail = *self.sq_tail;
+        let next = tail.wrapping_add(self.sq_pending);
+        if next.wrapping_sub(head) >= self.sq_entries {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock,
+                                      "submission queue is full"));
+        }
+        let index = next & self.sq_mask;
+        *self.sqes.offset(index as isize) = entry.0;
+        *self.sq_array.offset(index as isize) = index;
+        self.sq_pending += 1;
+        Ok(())
+    }
+
+    /// Hands everything pushed so far to the kernel, returning how many
+    /// entries it took.
+    pub fn submit(&mut self) -> io::Result<usize> {
+        self.submit_and_wait(0)
+    }
+
+    /// Like `submit`, but also blocks until at least `want` completions are
+    /// ready to be reaped.
+    pub fn submit_and_wait(&mut self, want: u32) -> io::Result<usize> {
+        let submit = unsafe {
+            // The entries have to be visible before the tail that covers them.
+            let tail = (*self.sq_tail).wrapping_add(self.sq_pending);
+            store_release(self.sq_tail, tail);
+            self.sq_pending = 0;
+            // The kernel may have taken fewer entries last time round (a
+            // short count, EAGAIN or EBUSY), so offer everything it hasn't
+            // consumed yet rather than just what was pushed since.
+            tail.wrapping_sub(load_acquire(self.sq_head))
+        };
+
+        let flags = if want > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+        let n = cvt(unsafe {
+            libc::syscall(libc::SYS_io_uring_enter, self.fd.raw(), submit, want, flags,
+                          ptr::null::<libc::sigset_t>(), 0usize) as c_int
+        })?;
+        Ok(n as usize)
+    }
+
+    /// Takes the next completion off the queue, if there is one.
+    pub fn completion(&mut self) -> Option<Completion> {
+        unsafe {
+            let head = *self.cq_head;
+            if head == load_acquire(self.cq_tail) {
+                return None;
+            }
+            let cqe = *self.cqes.offset((head & self.cq_mask) as isize);
+            // Hand the slot back only after we've copied it out.
+            store_release(self.cq_head, head.wrapping_add(1));
+            Some(Completion { user_data: cqe.user_data, res: cqe.res })
+        }
+    }
+
+    /// Appends every completion that is ready to `completions`.
+    pub fn completions(&mut self, completions: &mut Vec<Completion>) {
+        while let Some(c) = self.completion() {
+            completions.push(c);
+        }
+    }
+
+    /// Registers buffers for `read_fixed` and `write_fixed`, numbered in the
+    /// order given. Only one set can be registered at a time.
+    ///
+    /// This is unsafe because the kernel keeps using the memory until
+    /// `unregister_buffers` is called or the ring is dropped.
+    pub unsafe fn register_buffers(&mut self, bufs: &mut [&mut [u8]]) -> io::Result<()> {
+        let iovecs = bufs.iter_mut().map(|b| libc::iovec {
+            iov_base: b.as_mut_ptr() as *mut c_void,
+            iov_len: b.len(),
+        }).collect::<Vec<_>>();
+        cvt(libc::syscall(libc::SYS_io_uring_register, self.fd.raw(), IORING_REGISTER_BUFFERS,
+                          iovecs.as_ptr(), iovecs.len() as c_uint) as c_int)?;
+        Ok(())
+    }
+
+    pub fn unregister_buffers(&mut self) -> io::Result<()> {
+        cvt(unsafe {
+            libc::syscall(libc::SYS_io_uring_register, self.fd.raw(), IORING_UNREGISTER_BUFFERS,
+                          ptr::null::<c_void>(), 0 as c_uint) as c_int
+        })?;
+        Ok(())
+    }
+}
+
+// The ring indices are shared with the kernel, which updates them
+// concurrently, so they're read and written with the same ordering the
+// kernel's `smp_load_acquire`/`smp_store_release` pair expects.
+
+unsafe fn load_acquire(p: *const u32) -> u32 {
+    let v = ptr::read_volatile(p);
+    atomic::fence(Ordering::Acquire);
+    v
+}
+
+unsafe fn store_release(p: *mut u32, v: u32) {
+    atomic::fence(Ordering::Release);
+    ptr::write_volatile(p, v);
+}